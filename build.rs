@@ -5,7 +5,7 @@ use std::fs;
 use std::io::Write;
 use std::env;
 
-fn main() -> () {
+fn main() {
     if env::var("UPDATE_BUILD_INFO").is_ok() {
         let version = env!("CARGO_PKG_VERSION");
         let rustc_version_meta = rustc_version::version_meta();
@@ -23,8 +23,9 @@ fn main() -> () {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open("src/version.txt")
             .unwrap()
-            .write(&build_version.as_bytes());
+            .write(build_version.as_bytes());
     }
 }
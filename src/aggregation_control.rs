@@ -1,7 +1,8 @@
 use std::sync::mpsc;
 use file_handling::{AggregationMessages, FileHandlingMessages};
+use record_filters;
 use record_handling;
-use std::collections::HashMap;
+use system_names;
 use FileAggregation;
 use std::path::PathBuf;
 
@@ -17,14 +18,13 @@ impl AggregationController {
 
         AggregationController {
             agg_msg_receiver: agg_msg_rec,
-            file_handling_msg_senders: file_handling_msg_senders,
+            file_handling_msg_senders,
         }
     }
 
-    pub fn run_aggregation(&mut self, mut filenames: &mut Vec<PathBuf>) -> FileAggregation {
-        let mut number_of_raw_records = 0;
+    pub fn run_aggregation(&mut self, filenames: &mut Vec<PathBuf>) -> FileAggregation {
         let mut remaining_workers = self.file_handling_msg_senders.len();
-        let mut final_agg = HashMap::new();
+        let mut final_agg = FileAggregation::default();
         loop {
             match self.agg_msg_receiver.recv() {
                 Ok(AggregationMessages::Next(sender_id)) => {
@@ -35,10 +35,20 @@ impl AggregationController {
                         let _ = sender.send(FileHandlingMessages::Done);
                     }
                 }
-                Ok(AggregationMessages::Aggregate(num_parsed_records, new_agg)) => {
-                    debug!("Received new_agg having {} records.", new_agg.len());
-                    number_of_raw_records += num_parsed_records;
-                    record_handling::merge_aggregates(&new_agg, &mut final_agg);
+                Ok(AggregationMessages::Aggregate(new_agg)) => {
+                    let new_agg = *new_agg;
+                    debug!("Received new_agg having {} records.", new_agg.aggregation.len());
+                    final_agg.num_raw_records += new_agg.num_raw_records;
+                    record_handling::merge_aggregates(&new_agg.aggregation,
+                                                      &mut final_agg.aggregation);
+                    final_agg.file_formats.extend(new_agg.file_formats);
+                    system_names::merge_variants(new_agg.system_name_variants,
+                                                 &mut final_agg.system_name_variants);
+                    for (token_hash, num_requests) in new_agg.unknown_tokens {
+                        *final_agg.unknown_tokens.entry(token_hash).or_insert(0) += num_requests;
+                    }
+                    record_filters::merge_rejected_records(&new_agg.rejected_records,
+                                                           &mut final_agg.rejected_records);
                     remaining_workers -= 1;
                     if remaining_workers == 0 {
                         break;
@@ -48,10 +58,7 @@ impl AggregationController {
             }
         }
 
-        FileAggregation {
-            num_raw_records: number_of_raw_records,
-            aggregation: final_agg,
-        }
+        final_agg
     }
 }
 
@@ -62,7 +69,7 @@ mod aggregation_controller_unit_tests {
     use test_common;
     use std::path::PathBuf;
     use file_handling::{AggregationMessages, FileHandlingMessages};
-    use std::collections::HashMap;
+    use FileAggregation;
 
     #[test]
     fn run_aggregation_returns_when_all_of_the_file_handlers_have_sent_their_aggs() {
@@ -77,7 +84,7 @@ mod aggregation_controller_unit_tests {
         }
 
         for _ in 0..num_file_handlers {
-            let _ = agg_sndr.send(AggregationMessages::Aggregate(0, HashMap::new()));
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(Box::new(file_agg_having(0))));
         }
         let mut agg_ctrl = super::AggregationController::new(agg_recv, file_handler_senders);
         let file_agg = agg_ctrl.run_aggregation(&mut Vec::new());
@@ -103,8 +110,8 @@ mod aggregation_controller_unit_tests {
         let _ = agg_sndr.send(AggregationMessages::Next(file_handler_of_interest));
         // Once all of the file_handlers are finished the main loop of the controller will shutdown.
         for _ in 0..num_file_handlers {
-            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_common::TEST_LOG_FILE_AGGS,
-                                                                 HashMap::new()));
+            let file_agg = file_agg_having(test_common::TEST_LOG_FILE_AGGS);
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(Box::new(file_agg)));
         }
         let mut agg_ctrl = super::AggregationController::new(agg_recv, file_handler_senders);
         agg_ctrl.run_aggregation(&mut files);
@@ -136,8 +143,8 @@ mod aggregation_controller_unit_tests {
         }
         // Once all of the file_handlers are finished the main loop of the controller will shutdown.
         for _ in 0..num_file_handlers {
-            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_common::TEST_LOG_FILE_AGGS,
-                                                                 HashMap::new()));
+            let file_agg = file_agg_having(test_common::TEST_LOG_FILE_AGGS);
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(Box::new(file_agg)));
         }
         let mut agg_ctrl = super::AggregationController::new(agg_recv, file_handler_senders);
         agg_ctrl.run_aggregation(&mut files);
@@ -170,8 +177,8 @@ mod aggregation_controller_unit_tests {
         let _ = agg_sndr.send(AggregationMessages::Next(file_handler_of_interest));
         // Once all of the file_handlers are finished the main loop of the controller will shutdown.
         for _ in 0..num_file_handlers {
-            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_common::TEST_LOG_FILE_AGGS,
-                                                                 HashMap::new()));
+            let file_agg = file_agg_having(test_common::TEST_LOG_FILE_AGGS);
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(Box::new(file_agg)));
         }
         let mut agg_ctrl = super::AggregationController::new(agg_recv, file_handler_senders);
         agg_ctrl.run_aggregation(&mut files);
@@ -180,4 +187,8 @@ mod aggregation_controller_unit_tests {
         assert_eq!(received_msg.unwrap(),
         FileHandlingMessages::Filename(test_file_path_buf));
     }
-}
\ No newline at end of file
+
+    fn file_agg_having(num_raw_records: usize) -> FileAggregation {
+        FileAggregation { num_raw_records, ..FileAggregation::default() }
+    }
+}
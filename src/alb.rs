@@ -0,0 +1,377 @@
+use chrono::{DateTime, Utc};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use record_handling;
+
+// ALB records have grown new fields over time, always at the end of the record, so anything
+// after the trace ID is optional.
+const ALB_RECORD_MIN_FIELD_COUNT: usize = 18;
+const UNDEFINED_CHAR: &str = "-";
+const REQUEST_TYPES: [&str; 6] = ["http", "https", "h2", "grpcs", "ws", "wss"];
+
+/// The product of parsing a single AWS Application Load Balancer log record.
+#[derive(Debug)]
+pub struct ALBRecord<'a> {
+    pub request_type: &'a str,
+    pub timestamp: DateTime<Utc>,
+    pub elb_name: &'a str,
+    pub client_address: SocketAddr,
+    pub target_address: Option<SocketAddr>,
+    pub request_processing_time: f32,
+    pub target_processing_time: f32,
+    pub response_processing_time: f32,
+    pub elb_status_code: u16,
+    pub target_status_code: Option<u16>,
+    pub received_bytes: u64,
+    pub sent_bytes: u64,
+    pub request_method: &'a str,
+    pub request_url: &'a str,
+    pub request_http_version: &'a str,
+    pub user_agent: &'a str,
+    pub ssl_cipher: &'a str,
+    pub ssl_protocol: &'a str,
+    pub target_group_arn: &'a str,
+    pub trace_id: &'a str,
+    pub domain_name: &'a str,
+}
+
+/// The result of a failed attempt to parse an ALB record.
+#[derive(Debug, PartialEq)]
+pub struct ParsingErrors<'a> {
+    pub record: &'a str,
+    pub errors: Vec<ALBRecordParsingError>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ALBRecordParsingError {
+    MalformedRecord,
+    ParsingError {
+        field_name: ALBRecordField,
+        description: String,
+    },
+}
+
+impl Display for ALBRecordParsingError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ALBRecordParsingError::MalformedRecord => write!(f, "Record is malformed."),
+            ALBRecordParsingError::ParsingError { ref field_name, ref description } => {
+                write!(f,
+                       "Parsing of field {} failed with the following error: {}.",
+                       field_name,
+                       description)
+            }
+        }
+    }
+}
+
+impl Error for ALBRecordParsingError {
+    fn description(&self) -> &str {
+        match *self {
+            ALBRecordParsingError::MalformedRecord => "malformed record",
+            ALBRecordParsingError::ParsingError { .. } => "field parsing failed",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ALBRecordField {
+    RequestType = 0,
+    Timestamp,
+    ELBName,
+    ClientAddress,
+    TargetAddress,
+    RequestProcessingTime,
+    TargetProcessingTime,
+    ResponseProcessingTime,
+    ELBStatusCode,
+    TargetStatusCode,
+    ReceivedBytes,
+    SentBytes,
+    Request,
+    UserAgent,
+    SSLCipher,
+    SSLProtocol,
+    TargetGroupARN,
+    TraceID,
+    DomainName,
+}
+
+impl Display for ALBRecordField {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ALBRecordField::RequestType => write!(f, "request type"),
+            ALBRecordField::Timestamp => write!(f, "timestamp"),
+            ALBRecordField::ELBName => write!(f, "ELB name"),
+            ALBRecordField::ClientAddress => write!(f, "client address"),
+            ALBRecordField::TargetAddress => write!(f, "target address"),
+            ALBRecordField::RequestProcessingTime => write!(f, "request processing time"),
+            ALBRecordField::TargetProcessingTime => write!(f, "target processing time"),
+            ALBRecordField::ResponseProcessingTime => write!(f, "response processing time"),
+            ALBRecordField::ELBStatusCode => write!(f, "ELB status code"),
+            ALBRecordField::TargetStatusCode => write!(f, "target status code"),
+            ALBRecordField::ReceivedBytes => write!(f, "received bytes"),
+            ALBRecordField::SentBytes => write!(f, "sent bytes"),
+            ALBRecordField::Request => write!(f, "request"),
+            ALBRecordField::UserAgent => write!(f, "user agent"),
+            ALBRecordField::SSLCipher => write!(f, "SSL cipher"),
+            ALBRecordField::SSLProtocol => write!(f, "SSL protocol"),
+            ALBRecordField::TargetGroupARN => write!(f, "target group ARN"),
+            ALBRecordField::TraceID => write!(f, "trace ID"),
+            ALBRecordField::DomainName => write!(f, "domain name"),
+        }
+    }
+}
+
+/// Attempt to parse a single string into an ALB record.
+///
+/// Like `elp::parse_record` every field is parsed so that all of the faulty fields of a record
+/// are reported together.
+pub fn parse_record<'a>(record: &'a str) -> Result<ALBRecord<'a>, ParsingErrors<'a>> {
+    let mut errors = Vec::new();
+    let fields = split_record(record);
+    if fields.len() < ALB_RECORD_MIN_FIELD_COUNT ||
+       !REQUEST_TYPES.contains(&fields[ALBRecordField::RequestType as usize]) {
+        errors.push(ALBRecordParsingError::MalformedRecord);
+        return Err(ParsingErrors { record, errors });
+    }
+
+    let ts = parse_field(&fields, ALBRecordField::Timestamp, &mut errors);
+    let clnt_addr = parse_address_field(&fields, ALBRecordField::ClientAddress, &mut errors);
+    let tgt_addr = if fields[ALBRecordField::TargetAddress as usize] == UNDEFINED_CHAR {
+        Some(None)
+    } else {
+        parse_address_field(&fields, ALBRecordField::TargetAddress, &mut errors).map(Some)
+    };
+    let req_proc_time = parse_field(&fields, ALBRecordField::RequestProcessingTime, &mut errors);
+    let tgt_proc_time = parse_field(&fields, ALBRecordField::TargetProcessingTime, &mut errors);
+    let res_proc_time = parse_field(&fields, ALBRecordField::ResponseProcessingTime, &mut errors);
+    let elb_sc = parse_field(&fields, ALBRecordField::ELBStatusCode, &mut errors);
+    let tgt_sc = parse_optional_field(&fields, ALBRecordField::TargetStatusCode, &mut errors);
+    let bytes_received = parse_field(&fields, ALBRecordField::ReceivedBytes, &mut errors);
+    let bytes_sent = parse_field(&fields, ALBRecordField::SentBytes, &mut errors);
+    let mut request = fields[ALBRecordField::Request as usize].splitn(3, ' ');
+    let (method, url, http_version) = match (request.next(), request.next(), request.next()) {
+        (Some(method), Some(url), Some(http_version)) => (method, url, http_version),
+        _ => {
+            errors.push(ALBRecordParsingError::ParsingError {
+                field_name: ALBRecordField::Request,
+                description: "the request is not of the form \"METHOD URL VERSION\"".to_owned(),
+            });
+            (UNDEFINED_CHAR, UNDEFINED_CHAR, UNDEFINED_CHAR)
+        }
+    };
+
+    if errors.is_empty() {
+        // If errors is empty every parsed field is present and unwrap is safe.
+        Ok(ALBRecord {
+            request_type: fields[ALBRecordField::RequestType as usize],
+            timestamp: ts.unwrap(),
+            elb_name: fields[ALBRecordField::ELBName as usize],
+            client_address: clnt_addr.unwrap(),
+            target_address: tgt_addr.unwrap(),
+            request_processing_time: req_proc_time.unwrap(),
+            target_processing_time: tgt_proc_time.unwrap(),
+            response_processing_time: res_proc_time.unwrap(),
+            elb_status_code: elb_sc.unwrap(),
+            target_status_code: tgt_sc.unwrap(),
+            received_bytes: bytes_received.unwrap(),
+            sent_bytes: bytes_sent.unwrap(),
+            request_method: method,
+            request_url: url,
+            request_http_version: http_version,
+            user_agent: fields[ALBRecordField::UserAgent as usize],
+            ssl_cipher: fields[ALBRecordField::SSLCipher as usize],
+            ssl_protocol: fields[ALBRecordField::SSLProtocol as usize],
+            target_group_arn: fields[ALBRecordField::TargetGroupARN as usize],
+            trace_id: fields[ALBRecordField::TraceID as usize],
+            domain_name: fields
+                .get(ALBRecordField::DomainName as usize)
+                .cloned()
+                .unwrap_or(UNDEFINED_CHAR),
+        })
+    } else {
+        Err(ParsingErrors { record, errors })
+    }
+}
+
+// Splits a record on spaces while keeping double quoted fields, which may contain spaces and
+// escaped quotes, together. The quotes themselves are not part of the returned fields.
+fn split_record(record: &str) -> Vec<&str> {
+    let mut fields = Vec::with_capacity(ALB_RECORD_MIN_FIELD_COUNT + 12);
+    let record = record.trim();
+    let bytes = record.as_bytes();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'"' {
+            let start = idx + 1;
+            idx = start;
+            while idx < bytes.len() && bytes[idx] != b'"' {
+                idx += if bytes[idx] == b'\\' { 2 } else { 1 };
+            }
+            fields.push(&record[start..idx.min(bytes.len())]);
+            idx += 1;
+        } else {
+            let start = idx;
+            while idx < bytes.len() && bytes[idx] != b' ' {
+                idx += 1;
+            }
+            fields.push(&record[start..idx]);
+        }
+        while idx < bytes.len() && bytes[idx] == b' ' {
+            idx += 1;
+        }
+    }
+
+    fields
+}
+
+fn parse_field<T>(fields: &[&str],
+                  field_name: ALBRecordField,
+                  errors: &mut Vec<ALBRecordParsingError>)
+                  -> Option<T>
+    where T: FromStr,
+          T::Err: Display
+{
+    match fields[field_name as usize].parse::<T>() {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            errors.push(ALBRecordParsingError::ParsingError {
+                field_name,
+                description: e.to_string(),
+            });
+            None
+        }
+    }
+}
+
+// IPv6 addresses are logged without brackets, which SocketAddr's FromStr does not accept.
+fn parse_address_field(fields: &[&str],
+                       field_name: ALBRecordField,
+                       errors: &mut Vec<ALBRecordParsingError>)
+                       -> Option<SocketAddr> {
+    let address = record_handling::parse_socket_address(fields[field_name as usize]);
+    if address.is_none() {
+        errors.push(ALBRecordParsingError::ParsingError {
+            field_name,
+            description: "invalid socket address syntax".to_owned(),
+        });
+    }
+
+    address
+}
+
+// Fields such as the target status code are "-" when the load balancer never reached a target.
+fn parse_optional_field<T>(fields: &[&str],
+                           field_name: ALBRecordField,
+                           errors: &mut Vec<ALBRecordParsingError>)
+                           -> Option<Option<T>>
+    where T: FromStr,
+          T::Err: Display
+{
+    if fields[field_name as usize] == UNDEFINED_CHAR {
+        Some(None)
+    } else {
+        parse_field(fields, field_name, errors).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod parse_record_tests {
+
+    use super::{ALBRecordField, ALBRecordParsingError};
+
+    const TEST_RECORD: &str = "https 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 \
+        192.168.131.39:2817 10.0.0.1:80 0.086 0.048 0.037 200 200 0 57 \
+        \"GET https://www.example.com:443/404299/full?system=wpix HTTP/1.1\" \"curl/7.46.0\" \
+        ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 \
+        arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 \
+        \"Root=1-58337281-1d84f3d73c47ec4e58577259\" \"www.example.com\" \
+        \"arn:aws:acm:us-east-2:123456789012:certificate/12345678-1234-1234-1234-123456789012\" \
+        1 2018-07-02T22:22:48.364000Z \"authenticate,forward\" \"-\" \"-\" \"10.0.0.1:80\" \"200\" \
+        \"-\" \"-\"";
+
+    #[test]
+    fn parse_record_should_parse_all_of_the_fields_of_a_good_record() {
+        let record = super::parse_record(TEST_RECORD).unwrap();
+
+        assert_eq!(record.request_type, "https");
+        assert_eq!(record.elb_name, "app/my-loadbalancer/50dc6c495c0c9188");
+        assert_eq!(record.client_address, "192.168.131.39:2817".parse().unwrap());
+        assert_eq!(record.target_address, Some("10.0.0.1:80".parse().unwrap()));
+        assert_eq!(record.elb_status_code, 200);
+        assert_eq!(record.target_status_code, Some(200));
+        assert_eq!(record.sent_bytes, 57);
+        assert_eq!(record.request_method, "GET");
+        assert_eq!(record.request_url,
+                   "https://www.example.com:443/404299/full?system=wpix");
+        assert_eq!(record.request_http_version, "HTTP/1.1");
+        assert_eq!(record.user_agent, "curl/7.46.0");
+        assert_eq!(record.trace_id, "Root=1-58337281-1d84f3d73c47ec4e58577259");
+        assert_eq!(record.domain_name, "www.example.com");
+    }
+
+    #[test]
+    fn parse_record_should_accept_a_missing_target() {
+        let record = TEST_RECORD.replace("10.0.0.1:80 0.086 0.048 0.037 200 200",
+                                         "- -1 -1 -1 460 -");
+
+        let record = super::parse_record(&record).unwrap();
+
+        assert_eq!(record.target_address, None);
+        assert_eq!(record.target_status_code, None);
+        assert_eq!(record.elb_status_code, 460);
+    }
+
+    #[test]
+    fn parse_record_should_accept_ipv6_addresses() {
+        let record = TEST_RECORD.replace("192.168.131.39:2817 10.0.0.1:80",
+                                         "2001:db8::1:2817 [2001:db8::2]:80");
+
+        let record = super::parse_record(&record).unwrap();
+
+        assert_eq!(record.client_address, "[2001:db8::1]:2817".parse().unwrap());
+        assert_eq!(record.target_address, Some("[2001:db8::2]:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_record_should_keep_quoted_fields_with_spaces_together() {
+        let record = TEST_RECORD.replace("\"curl/7.46.0\"", "\"Mozilla/5.0 (X11; \\\"Linux\\\")\"");
+
+        let record = super::parse_record(&record).unwrap();
+
+        assert_eq!(record.user_agent, "Mozilla/5.0 (X11; \\\"Linux\\\")");
+        assert_eq!(record.ssl_protocol, "TLSv1.2");
+    }
+
+    #[test]
+    fn parse_record_should_return_a_malformed_record_error_for_classic_elb_records() {
+        let record = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\"";
+
+        let errs = super::parse_record(record).unwrap_err();
+
+        assert_eq!(errs.errors, vec![ALBRecordParsingError::MalformedRecord]);
+    }
+
+    #[test]
+    fn parse_record_should_return_an_error_for_each_bad_field() {
+        let record = TEST_RECORD.replace("0.086 0.048", "fast 0.048").replace(" 200 200 ", " 200 OK ");
+
+        let errs = super::parse_record(&record).unwrap_err();
+
+        let bad_fields = errs.errors
+            .iter()
+            .map(|err| match *err {
+                ALBRecordParsingError::ParsingError { field_name, .. } => field_name,
+                ALBRecordParsingError::MalformedRecord => panic!("Unexpected malformed record."),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(bad_fields,
+                   vec![ALBRecordField::RequestProcessingTime, ALBRecordField::TargetStatusCode]);
+    }
+}
@@ -1,27 +1,109 @@
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
+use std::str;
+use chrono::{DateTime, Datelike, Duration as TimeDuration, NaiveDate, NaiveDateTime, TimeZone,
+             Utc};
+use flate2::read::MultiGzDecoder;
+use bzip2::read::MultiBzDecoder;
 use walkdir;
-use walkdir::WalkDir;
-use ELBRecordAggregation;
-use std::collections::HashMap;
+use walkdir::{WalkDir, WalkDirIterator};
+use glob::{MatchOptions, Pattern};
+use FileAggregation;
 use record_handling;
+use record_handling::{AggregationConfig, LogFormat};
+use record_filters::TimeRange;
+use std::sync::Arc;
 use std::io::Write;
 use std::sync::mpsc;
 use std::sync::mpsc::RecvTimeoutError;
 use std::time::Duration;
 use std::path::{Path, PathBuf};
+use std::collections::HashSet;
 
-pub fn file_list(dir: &Path) -> Result<Vec<PathBuf>, walkdir::Error> {
+/// The log location that stands for the records piped to stdin.
+pub const STDIN_PATH: &str = "-";
+
+pub const LOG_FILE_EXTENSIONS: [&str; 3] = ["log", "gz", "bz2"];
+
+/// Controls which of the files below the log location are read.
+#[derive(Clone, Debug)]
+pub struct FileDiscoveryConfig {
+    /// Files and directories whose names show that they only hold records outside of the
+    /// range are skipped.
+    pub time_range: TimeRange,
+    /// When it isn't empty, only the files matching one of the patterns are read. Patterns are
+    /// matched against the paths relative to the log location.
+    pub include: Vec<Pattern>,
+    /// The files and directories matching any of the patterns are skipped.
+    pub exclude: Vec<Pattern>,
+    /// The suffixes, without the leading dot, of the files that are read.
+    pub extensions: Vec<String>,
+    pub follow_links: bool,
+    /// The depth below the log location the walk stops at. The log location is at depth 0.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for FileDiscoveryConfig {
+    fn default() -> FileDiscoveryConfig {
+        FileDiscoveryConfig {
+            time_range: TimeRange::default(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            extensions: LOG_FILE_EXTENSIONS.iter().map(|ext| (*ext).to_owned()).collect(),
+            follow_links: false,
+            max_depth: None,
+        }
+    }
+}
+
+impl FileDiscoveryConfig {
+    fn has_log_extension(&self, path: &Path) -> bool {
+        let file_name = path.file_name().and_then(|file_name| file_name.to_str()).unwrap_or("");
+        self.extensions.iter().any(|ext| {
+            file_name.len() > ext.len() + 1 && file_name.ends_with(ext.as_str()) &&
+            file_name[..file_name.len() - ext.len()].ends_with('.')
+        })
+    }
+}
+
+// `*` and `?` don't match a path separator, so `**` is needed to match across directories.
+const GLOB_MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Lists the log files below `dir` that the discovery config selects.
+pub fn file_list(dir: &Path,
+                 discovery: &FileDiscoveryConfig)
+                 -> Result<Vec<PathBuf>, walkdir::Error> {
     let mut filenames = Vec::new();
-    let dir_entries = WalkDir::new(dir);
+    let mut walk_dir = WalkDir::new(dir).follow_links(discovery.follow_links);
+    if let Some(max_depth) = discovery.max_depth {
+        walk_dir = walk_dir.max_depth(max_depth);
+    }
+    let relative_path = |path: &Path| path.strip_prefix(dir).unwrap_or(path).to_path_buf();
+    let dir_entries = walk_dir.into_iter().filter_entry(|dir_entry| {
+        // The root is always walked, whatever its name.
+        dir_entry.depth() == 0 ||
+        (!discovery.exclude
+            .iter()
+            .any(|pattern| {
+                pattern.matches_path_with(&relative_path(dir_entry.path()), GLOB_MATCH_OPTIONS)
+            }) &&
+         path_time_window(dir_entry.path(), dir_entry.file_type().is_dir())
+            .is_none_or(|(start, end)| discovery.time_range.overlaps(&start, &end)))
+    });
     for entry in dir_entries {
         let dir_entry = entry?;
-        if dir_entry
-            .path()
-            .extension()
-            .map(|ext| ext.eq("log"))
-            .unwrap_or(false) {
+        let included = discovery.include.is_empty() ||
+                       discovery.include.iter().any(|pattern| {
+            pattern.matches_path_with(&relative_path(dir_entry.path()), GLOB_MATCH_OPTIONS)
+        });
+        if !dir_entry.file_type().is_dir() && included &&
+           discovery.has_log_extension(dir_entry.path()) {
             filenames.push(dir_entry.path().to_path_buf());
         }
     }
@@ -29,9 +111,176 @@ pub fn file_list(dir: &Path) -> Result<Vec<PathBuf>, walkdir::Error> {
     Ok(filenames)
 }
 
+/// Lists the log files of all of the log locations. Files and stdin, `-`, are read as they are
+/// given, without checking them against the discovery config, while directories are walked.
+pub fn log_file_list(locations: &[PathBuf],
+                     discovery: &FileDiscoveryConfig)
+                     -> Result<Vec<PathBuf>, walkdir::Error> {
+    let mut filenames = Vec::new();
+    for location in locations {
+        if location == Path::new(STDIN_PATH) || location.is_file() {
+            filenames.push(location.clone());
+        } else {
+            filenames.extend(file_list(location, discovery)?);
+        }
+    }
+    // Stdin can only be read once, as can any file given twice.
+    let mut seen = HashSet::new();
+    filenames.retain(|filename| seen.insert(filename.clone()));
+
+    Ok(filenames)
+}
+
+/// Reads a list of log locations, one to a line. Blank lines are skipped.
+pub fn read_location_list(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut locations = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let location = line.trim();
+        if !location.is_empty() {
+            locations.push(PathBuf::from(location));
+        }
+    }
+
+    Ok(locations)
+}
+
+// Records can be written to the file or directory after the one their timestamp belongs to, and
+// ELB log files cover up to an hour before their timestamp, so the windows are widened by this
+// much on either side.
+const TIME_WINDOW_MARGIN_HOURS: i64 = 2;
+
+// Returns the period a path holds the records of, from the timestamp in an ELB or ALB log file
+// name, such as 123456789012_elasticloadbalancing_us-east-1_my-elb_20150815T2345Z_..., or from
+// the trailing YYYY/MM/DD, YYYY/MM or YYYY directories of an S3 style layout, which are counted
+// back from a directory itself and from the parent directory of a file.
+fn path_time_window(path: &Path, is_dir: bool) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let margin = TimeDuration::hours(TIME_WINDOW_MARGIN_HOURS);
+    let file_name = path.file_name().and_then(|file_name| file_name.to_str()).unwrap_or("");
+    let file_timestamp = file_name.split('_')
+        .filter_map(|part| NaiveDateTime::parse_from_str(part, "%Y%m%dT%H%MZ").ok())
+        .next();
+    if let Some(timestamp) = file_timestamp {
+        let timestamp = Utc.from_utc_datetime(&timestamp);
+        return Some((timestamp - margin, timestamp + margin));
+    }
+
+    let dir = if is_dir { path } else { path.parent()? };
+    let (start, end) = date_directories_window(dir)?;
+    Some((Utc.from_utc_datetime(&start.and_hms_opt(0, 0, 0)?) - margin,
+          Utc.from_utc_datetime(&end.and_hms_opt(0, 0, 0)?) + margin))
+}
+
+// A date is only read from the directories at the end of `dir`, and not when the directory in
+// front of them is numeric too, so that a numeric directory below the date directories, as in
+// 2015/08/15/1234, isn't taken as a year. Files whose date can't be read are kept and their
+// records are filtered one by one.
+fn date_directories_window(dir: &Path) -> Option<(NaiveDate, NaiveDate)> {
+    let components = dir.components()
+        .map(|component| component.as_os_str().to_str().unwrap_or(""))
+        .collect::<Vec<_>>();
+    let (year, month, day) = (1..4)
+        .rev()
+        .filter(|num_parts| components.len() >= *num_parts)
+        .filter_map(|num_parts| {
+            let date_start = components.len() - num_parts;
+            let parts = &components[date_start..];
+            let year = date_part(parts[0], 4)? as i32;
+            let month = match parts.get(1) {
+                Some(part) => Some(date_part(part, 2)?),
+                None => None,
+            };
+            let day = match parts.get(2) {
+                Some(part) => Some(date_part(part, 2)?),
+                None => None,
+            };
+            Some((date_start, (year, month, day)))
+        })
+        .next()
+        .filter(|&(date_start, _)| {
+            date_start == 0 || !is_numeric(components[date_start - 1])
+        })
+        .map(|(_, date)| date)?;
+    match (month, day) {
+        (Some(month), Some(day)) => {
+            let start = NaiveDate::from_ymd_opt(year, month, day)?;
+            Some((start, start.succ_opt()?))
+        }
+        (Some(month), None) => {
+            let start = NaiveDate::from_ymd_opt(year, month, 1)?;
+            let end = if month == 12 {
+                NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+            } else {
+                NaiveDate::from_ymd_opt(year, month + 1, 1)?
+            };
+            Some((start, end))
+        }
+        (None, _) => {
+            let start = NaiveDate::from_ymd_opt(year, 1, 1)?;
+            Some((start, start.with_year(year + 1)?))
+        }
+    }
+}
+
+fn is_numeric(component: &str) -> bool {
+    !component.is_empty() && component.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+fn date_part(component: &str, num_digits: usize) -> Option<u32> {
+    if component.len() == num_digits && is_numeric(component) {
+        component.parse().ok()
+    } else {
+        None
+    }
+}
+
+const GZIP_MAGIC_BYTES: &[u8] = &[0x1f, 0x8b];
+const BZIP2_MAGIC_BYTES: &[u8] = b"BZh";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compression {
+    Uncompressed,
+    Gzip,
+    Bzip2,
+}
+
+impl Compression {
+    // The extension wins when it names a compression format so that a corrupt archive is
+    // reported as such instead of being counted as garbage records. Otherwise the magic bytes
+    // decide, which catches compressed files that were saved with a plain .log extension.
+    fn detect(path: &Path, header: &[u8]) -> Compression {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("bz2") => Compression::Bzip2,
+            _ if header.starts_with(GZIP_MAGIC_BYTES) => Compression::Gzip,
+            _ if header.starts_with(BZIP2_MAGIC_BYTES) => Compression::Bzip2,
+            _ => Compression::Uncompressed,
+        }
+    }
+}
+
+fn open_log_file(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let input: Box<dyn Read> = if path == Path::new(STDIN_PATH) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut reader = BufReader::new(input);
+    let compression = Compression::detect(path, reader.fill_buf()?);
+    debug!("Reading file {} as {:?}.", path.display(), compression);
+    let log_reader: Box<dyn BufRead> = match compression {
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Compression::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+        Compression::Uncompressed => Box::new(reader),
+    };
+
+    Ok(log_reader)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum AggregationMessages {
-    Aggregate(usize, ELBRecordAggregation),
+    Aggregate(Box<FileAggregation>),
     Next(usize),
 }
 
@@ -44,32 +293,95 @@ pub enum FileHandlingMessages {
 #[derive(Debug)]
 enum FileHandlingErrors<'a> {
     FileReadError { path: &'a Path, err: io::Error },
+    ReadInterrupted {
+        path: &'a Path,
+        line_num: usize,
+        err: io::Error,
+    },
     LineReadError {
         path: &'a Path,
         line_nums: Vec<usize>,
     },
 }
 
+// The number of non-empty lines used to detect the format of a file.
+const FORMAT_SNIFFING_LINES: usize = 10;
+
+/// What a dry run reports about a log file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    /// `None` for stdin.
+    pub size: Option<u64>,
+    pub modified: Option<DateTime<Utc>>,
+    /// The configured format or the format detected from the first records of the file.
+    /// `None` when no record of the file can be parsed, and for stdin, which isn't read so
+    /// that it can still be aggregated.
+    pub log_format: Option<LogFormat>,
+}
+
+pub fn manifest_entry(path: &Path, log_format: Option<LogFormat>) -> io::Result<ManifestEntry> {
+    if path == Path::new(STDIN_PATH) {
+        return Ok(ManifestEntry {
+            path: path.to_path_buf(),
+            size: None,
+            modified: None,
+            log_format,
+        });
+    }
+
+    let metadata = fs::metadata(path)?;
+    let log_format = match log_format {
+        Some(log_format) => Some(log_format),
+        None => sniff_log_format(path)?,
+    };
+    Ok(ManifestEntry {
+        path: path.to_path_buf(),
+        size: Some(metadata.len()),
+        modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        log_format,
+    })
+}
+
+fn sniff_log_format(path: &Path) -> io::Result<Option<LogFormat>> {
+    let mut reader = open_log_file(path)?;
+    let mut sample = Vec::new();
+    let mut line = Vec::new();
+    while sample.len() < FORMAT_SNIFFING_LINES {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if let Ok(record) = str::from_utf8(&line) {
+            let record = record.trim_end_matches('\n').trim_end_matches('\r');
+            if !record.trim().is_empty() {
+                sample.push(record.to_owned());
+            }
+        }
+    }
+
+    Ok(LogFormat::detect(&sample.iter().map(|record| record.as_str()).collect::<Vec<_>>()))
+}
+
 #[derive(Debug)]
 pub struct FileAggregator {
     id: usize,
-    num_raw_records: usize,
-    final_agg: ELBRecordAggregation,
+    config: Arc<AggregationConfig>,
+    final_agg: FileAggregation,
 }
 
 impl FileAggregator {
-    pub fn new(id: usize) -> FileAggregator {
+    pub fn new(id: usize, config: Arc<AggregationConfig>) -> FileAggregator {
         FileAggregator {
-            id: id,
-            num_raw_records: 0,
-            final_agg: HashMap::new(),
+            id,
+            config,
+            final_agg: FileAggregation::default(),
         }
     }
 
     pub fn run(mut self,
                filename_receiver: &mpsc::Receiver<FileHandlingMessages>,
-               aggregate_sender: &mpsc::Sender<AggregationMessages>)
-               -> () {
+               aggregate_sender: &mpsc::Sender<AggregationMessages>) {
         let _ = aggregate_sender.send(AggregationMessages::Next(self.id));
         let timeout = Duration::from_millis(10000);
         loop {
@@ -96,16 +408,15 @@ impl FileAggregator {
                             This is unrecoverable error and should be reported to the developers.",
                                       self.id);
                     println_stderr!("{}", msg);
-                    panic!(msg);
+                    panic!("{}", msg);
                 }
             }
         }
 
-        let _ = aggregate_sender.send(AggregationMessages::Aggregate(self.num_raw_records,
-                                                                     self.final_agg));
+        let _ = aggregate_sender.send(AggregationMessages::Aggregate(Box::new(self.final_agg)));
     }
 
-    fn aggregate_file(&mut self, file_path: &Path) -> () {
+    fn aggregate_file(&mut self, file_path: &Path) {
         debug!("FileAggregator {} received filename {}.",
         self.id,
         file_path.display());
@@ -115,6 +426,12 @@ impl FileAggregator {
                                 path.display(),
                                 err)
             }
+            Err(FileHandlingErrors::ReadInterrupted { path, line_num, err }) => {
+                println_stderr!("Stopped reading file {} at line {} with error {}. ",
+                                path.display(),
+                                line_num,
+                                err)
+            }
             Err(FileHandlingErrors::LineReadError { path, line_nums }) => {
                 println_stderr!("Failed to read lines {:?} from file {}. ",
                                 line_nums,
@@ -126,67 +443,118 @@ impl FileAggregator {
 
     fn read_file<'a>(&mut self, path: &'a Path) -> Result<(), FileHandlingErrors<'a>> {
         debug!("Processing file {}.", path.display());
-        match File::open(path) {
-            Ok(file) => self.read_records(path, &file),
-            Err(err) => {
-                Err(FileHandlingErrors::FileReadError {
-                    path: path,
-                    err: err,
-                })
-            }
+        match open_log_file(path) {
+            Ok(reader) => self.read_records(path, reader),
+            Err(err) => Err(FileHandlingErrors::FileReadError { path, err }),
         }
     }
 
-    fn read_records<'a>(&mut self,
-                        path: &'a Path,
-                        file: &File)
-                        -> Result<(), FileHandlingErrors<'a>> {
+    fn read_records<'a, R: BufRead>(&mut self,
+                                    path: &'a Path,
+                                    mut reader: R)
+                                    -> Result<(), FileHandlingErrors<'a>> {
         let mut bad_line_nums = Vec::new();
         let mut records_processed = 0;
-        for (line_num, possible_record) in BufReader::new(file).lines().enumerate() {
-            if let Ok(record) = possible_record {
-                record_handling::try_parse_record(&record, &mut self.final_agg);
-                records_processed += 1;
-            } else {
-                bad_line_nums.push(line_num);
+        let mut line_num = 0;
+        let mut line = Vec::new();
+        let mut log_format = self.config.log_format;
+        // Records are held back until enough of them have been seen to detect the format.
+        let mut sniffed_records = Vec::new();
+        let mut num_sniffed_records = 0;
+        // Lines are read as bytes so that a line that isn't valid UTF-8 can be skipped while an
+        // I/O error, such as a corrupt compressed stream, stops the read instead of repeating
+        // forever.
+        let read_result = loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {
+                    if let Ok(record) = str::from_utf8(&line) {
+                        let record = record.trim_end_matches('\n').trim_end_matches('\r');
+                        if let Some(format) = log_format {
+                            record_handling::try_parse_record(record,
+                                                              format,
+                                                              &self.config,
+                                                              &mut self.final_agg);
+                        } else {
+                            sniffed_records.push(record.to_owned());
+                            if !record.trim().is_empty() {
+                                num_sniffed_records += 1;
+                            }
+                            if num_sniffed_records == FORMAT_SNIFFING_LINES {
+                                log_format = Some(self.parse_sniffed_records(&sniffed_records));
+                                sniffed_records.clear();
+                            }
+                        }
+                        records_processed += 1;
+                    } else {
+                        bad_line_nums.push(line_num);
+                    }
+                }
+                Err(err) => break Err(err),
             }
-        }
+            line_num += 1;
+        };
+        let log_format = log_format
+            .unwrap_or_else(|| self.parse_sniffed_records(&sniffed_records));
+        self.final_agg.file_formats.push((path.to_path_buf(), log_format));
 
         debug!("Found {} records in file {}.",
         records_processed,
         path.display());
-        self.num_raw_records += records_processed;
-        if bad_line_nums.is_empty() {
-            Ok(())
-        } else {
-            Err(FileHandlingErrors::LineReadError {
-                line_nums: bad_line_nums,
-                path: path,
-            })
+        self.final_agg.num_raw_records += records_processed;
+        match read_result {
+            Err(err) => Err(FileHandlingErrors::ReadInterrupted { path, line_num, err }),
+            Ok(()) if !bad_line_nums.is_empty() => {
+                Err(FileHandlingErrors::LineReadError {
+                    line_nums: bad_line_nums,
+                    path,
+                })
+            }
+            Ok(()) => Ok(()),
         }
     }
+
+    // Files in which no record can be parsed are treated as Classic ELB logs so that their
+    // records are reported as unparsable.
+    fn parse_sniffed_records(&mut self, sniffed_records: &[String]) -> LogFormat {
+        let sample = sniffed_records
+            .iter()
+            .map(|record| record.as_str())
+            .filter(|record| !record.trim().is_empty())
+            .collect::<Vec<_>>();
+        let log_format = LogFormat::detect(&sample).unwrap_or(LogFormat::ClassicELB);
+        for record in sniffed_records {
+            record_handling::try_parse_record(record,
+                                              log_format,
+                                              &self.config,
+                                              &mut self.final_agg);
+        }
+
+        log_format
+    }
 }
 
 #[cfg(test)]
 mod file_aggregator_run_tests {
 
-    use std::sync::mpsc;
+    use std::sync::{mpsc, Arc};
 
     #[test]
     fn sends_the_final_agg_after_receiving_the_done_message() {
         let (filename_sender, filename_receiver) = mpsc::channel();
         let (agg_sender, agg_receiver) = mpsc::channel();
         let _ = filename_sender.send(super::FileHandlingMessages::Done);
-        let file_aggregator = super::FileAggregator::new(1);
+        let file_aggregator = super::FileAggregator::new(1, Arc::default());
         file_aggregator.run(&filename_receiver, &agg_sender);
 
         // Dump the AggregationMessages::Next message sent at startup.
         let _ = agg_receiver.recv();
 
         match agg_receiver.recv().unwrap() {
-            super::AggregationMessages::Aggregate(size, agg) => {
-                assert_eq!(size, 0);
-                assert_eq!(agg.len(), 0);
+            super::AggregationMessages::Aggregate(file_agg) => {
+                assert_eq!(file_agg.num_raw_records, 0);
+                assert_eq!(file_agg.aggregation.len(), 0);
             }
             super::AggregationMessages::Next(_) => panic!("Received an unexpected Next message."),
         }
@@ -197,13 +565,13 @@ mod file_aggregator_run_tests {
         let (filename_sender, filename_receiver) = mpsc::channel();
         let (agg_sender, agg_receiver) = mpsc::channel();
         let _ = filename_sender.send(super::FileHandlingMessages::Done);
-        let file_aggregator = super::FileAggregator::new(1);
+        let file_aggregator = super::FileAggregator::new(1, Arc::default());
 
         file_aggregator.run(&filename_receiver, &agg_sender);
 
         match agg_receiver.recv().unwrap() {
             super::AggregationMessages::Next(id) => assert_eq!(id, 1),
-            super::AggregationMessages::Aggregate(_, _) => panic!("Received an unexpected Aggregate message."),
+            super::AggregationMessages::Aggregate(_) => panic!("Received an unexpected Aggregate message."),
         }
     }
 }
@@ -212,26 +580,77 @@ mod file_aggregator_run_tests {
 mod file_aggregator_read_records {
 
     use std::fs::File;
+    use std::io::BufReader;
     use std::path::Path;
+    use std::sync::Arc;
     use test_common;
 
     #[test]
     fn read_records() {
         let path = Path::new(test_common::TEST_LOG_FILE);
-        let file = File::open(&path).unwrap();
-        let mut file_aggregator = super::FileAggregator::new(0);
+        let file = File::open(path).unwrap();
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::default());
 
 
-        let _ = file_aggregator.read_records(&path, &file);
+        let _ = file_aggregator.read_records(path, BufReader::new(file));
 
-        assert_eq!(file_aggregator.final_agg.len(),
+        assert_eq!(file_aggregator.final_agg.aggregation.len(),
         test_common::TEST_LOG_FILE_AGGS)
     }
+
+    #[test]
+    fn read_records_should_return_an_error_when_the_stream_is_corrupt() {
+        let path = Path::new(test_common::TEST_GZIP_LOG_FILE);
+        let corrupt_stream = super::MultiGzDecoder::new(&b"\x1f\x8bnot really gzip"[..]);
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::default());
+
+        let result = file_aggregator.read_records(path, BufReader::new(corrupt_stream));
+
+        match result {
+            Err(super::FileHandlingErrors::ReadInterrupted { line_num, .. }) => {
+                assert_eq!(line_num, 0)
+            }
+            other => panic!("Expected a ReadInterrupted but received {:?}.", other),
+        }
+    }
 }
 
 #[cfg(test)]
-mod file_aggregator_process_file_tests {
+mod compression_tests {
+
     use std::path::Path;
+    use super::Compression;
+
+    #[test]
+    fn detect_should_use_the_extension_when_it_names_a_compression_format() {
+        assert_eq!(Compression::detect(Path::new("elb.log.gz"), b"2016-12-05"),
+                   Compression::Gzip);
+        assert_eq!(Compression::detect(Path::new("elb.log.bz2"), b"2016-12-05"),
+                   Compression::Bzip2);
+    }
+
+    #[test]
+    fn detect_should_use_the_magic_bytes_when_the_extension_is_not_a_compression_format() {
+        assert_eq!(Compression::detect(Path::new("elb.log"), &[0x1f, 0x8b, 0x08]),
+                   Compression::Gzip);
+        assert_eq!(Compression::detect(Path::new("elb.log"), b"BZh91AY"),
+                   Compression::Bzip2);
+    }
+
+    #[test]
+    fn detect_should_return_uncompressed_for_plain_log_files() {
+        assert_eq!(Compression::detect(Path::new("elb.log"), b"2016-12-05"),
+                   Compression::Uncompressed);
+        assert_eq!(Compression::detect(Path::new("elb.log"), b""),
+                   Compression::Uncompressed);
+    }
+}
+
+#[cfg(test)]
+mod file_aggregator_process_file_tests {
+    use std::path::{Path, PathBuf};
+    use record_handling::{AggregationConfig, LogFormat};
+    use std::sync::Arc;
     use std::fs::File;
     use std::io::{BufRead, BufReader};
     use test_common;
@@ -243,32 +662,187 @@ mod file_aggregator_process_file_tests {
             .collect::<Vec<_>>()
             .len();
         let log_path = Path::new(test_common::TEST_LOG_FILE);
-        let mut file_aggregator = super::FileAggregator::new(0);
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::default());
+
+        let _ = file_aggregator.read_file(log_path);
+
+        assert_eq!(file_aggregator.final_agg.num_raw_records, num_lines)
+    }
+
+    #[test]
+    fn process_file_should_read_gzip_compressed_files() {
+        let log_path = Path::new(test_common::TEST_GZIP_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::default());
+
+        let result = file_aggregator.read_file(log_path);
+
+        assert!(result.is_ok());
+        assert_eq!(file_aggregator.final_agg.aggregation.len(),
+                   test_common::TEST_LOG_FILE_AGGS)
+    }
+
+    #[test]
+    fn process_file_should_read_bzip2_compressed_files() {
+        let log_path = Path::new(test_common::TEST_BZIP2_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::default());
+
+        let result = file_aggregator.read_file(log_path);
 
-        let _ = file_aggregator.read_file(&log_path);
+        assert!(result.is_ok());
+        assert_eq!(file_aggregator.final_agg.aggregation.len(),
+                   test_common::TEST_LOG_FILE_AGGS)
+    }
+
+    #[test]
+    fn process_file_should_produce_the_same_aggregates_for_alb_logs() {
+        let log_path = Path::new(test_common::TEST_ALB_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::default());
+
+        let result = file_aggregator.read_file(log_path);
 
-        assert_eq!(file_aggregator.num_raw_records, num_lines)
+        assert!(result.is_ok());
+        assert_eq!(file_aggregator.final_agg.aggregation.len(),
+                   test_common::TEST_LOG_FILE_AGGS)
+    }
+
+    #[test]
+    fn process_file_should_record_the_detected_format_of_each_file() {
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::default());
+
+        let _ = file_aggregator.read_file(Path::new(test_common::TEST_LOG_FILE));
+        let _ = file_aggregator.read_file(Path::new(test_common::TEST_ALB_LOG_FILE));
+
+        assert_eq!(file_aggregator.final_agg.file_formats,
+                   vec![(PathBuf::from(test_common::TEST_LOG_FILE), LogFormat::ClassicELB),
+                        (PathBuf::from(test_common::TEST_ALB_LOG_FILE), LogFormat::ALB)])
+    }
+
+    #[test]
+    fn process_file_should_use_the_log_format_override_instead_of_detecting_the_format() {
+        let log_path = Path::new(test_common::TEST_ALB_LOG_FILE);
+        let config = AggregationConfig {
+            log_format: Some(LogFormat::ClassicELB),
+            ..AggregationConfig::default()
+        };
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::new(config));
+
+        let _ = file_aggregator.read_file(log_path);
+
+        assert_eq!(file_aggregator.final_agg.aggregation.len(), 0);
+        assert_eq!(file_aggregator.final_agg.file_formats,
+                   vec![(log_path.to_path_buf(), LogFormat::ClassicELB)])
     }
 
     #[test]
     fn process_file_should_return_an_error_when_the_file_cannot_be_opened() {
         let log_path = Path::new("bad_filename");
-        let mut file_aggregator = super::FileAggregator::new(0);
+        let mut file_aggregator = super::FileAggregator::new(0, Arc::default());
 
-        let result = file_aggregator.read_file(&log_path);
+        let result = file_aggregator.read_file(log_path);
 
         assert!(result.is_err())
     }
 }
 
+#[cfg(test)]
+mod manifest_entry_tests {
+
+    use std::path::Path;
+    use record_handling::LogFormat;
+    use test_common;
+
+    #[test]
+    fn manifest_entry_should_return_the_size_and_the_detected_format_of_a_file() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+
+        let entry = super::manifest_entry(path, None).unwrap();
+
+        assert_eq!(entry.size, Some(path.metadata().unwrap().len()));
+        assert!(entry.modified.is_some());
+        assert_eq!(entry.log_format, Some(LogFormat::ClassicELB))
+    }
+
+    #[test]
+    fn manifest_entry_should_detect_the_format_of_compressed_and_alb_files() {
+        let gzipped = super::manifest_entry(Path::new("./test_artifacts/test_elb_log_file.log.gz"),
+                                            None)
+            .unwrap();
+        let alb = super::manifest_entry(Path::new("./test_artifacts/test_alb_log_file.log"), None)
+            .unwrap();
+
+        assert_eq!(gzipped.log_format, Some(LogFormat::ClassicELB));
+        assert_eq!(alb.log_format, Some(LogFormat::ALB))
+    }
+
+    #[test]
+    fn manifest_entry_should_not_read_stdin() {
+        let entry = super::manifest_entry(Path::new("-"), None).unwrap();
+
+        assert_eq!(entry.size, None);
+        assert_eq!(entry.log_format, None)
+    }
+
+    #[test]
+    fn manifest_entry_should_return_an_error_when_the_file_is_missing() {
+        assert!(super::manifest_entry(Path::new("./test_artifacts/missing.log"), None).is_err())
+    }
+}
+
+#[cfg(test)]
+mod path_time_window_tests {
+
+    use std::path::Path;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn path_time_window_should_use_the_timestamp_in_an_elb_log_file_name() {
+        let path = Path::new("/logs/123456789012_elasticloadbalancing_us-east-1_my-elb_\
+                              20150815T2345Z_172.160.1.6_5y8gctne.log");
+
+        assert_eq!(super::path_time_window(path, false),
+                   Some((Utc.with_ymd_and_hms(2015, 8, 15, 21, 45, 0).unwrap(),
+                         Utc.with_ymd_and_hms(2015, 8, 16, 1, 45, 0).unwrap())));
+    }
+
+    #[test]
+    fn path_time_window_should_use_the_date_directories_of_the_path() {
+        let day = |year, month, day, hour| Utc.with_ymd_and_hms(year, month, day, hour, 0, 0)
+            .unwrap();
+
+        assert_eq!(super::path_time_window(Path::new("/logs/2015/08/15/elb.log"), false),
+                   Some((day(2015, 8, 14, 22), day(2015, 8, 16, 2))));
+        assert_eq!(super::path_time_window(Path::new("/logs/2015/12"), true),
+                   Some((day(2015, 11, 30, 22), day(2016, 1, 1, 2))));
+        assert_eq!(super::path_time_window(Path::new("/logs/2015"), true),
+                   Some((day(2014, 12, 31, 22), day(2016, 1, 1, 2))));
+        assert_eq!(super::path_time_window(Path::new("/logs/elb.log"), false), None);
+    }
+
+    #[test]
+    fn path_time_window_should_only_read_the_date_from_the_trailing_directories() {
+        assert_eq!(super::path_time_window(Path::new("/logs/2015/08/15/1234/elb.log"), false),
+                   None);
+        assert_eq!(super::path_time_window(Path::new("/logs/2015/08/15/1234"), true), None);
+        assert_eq!(super::path_time_window(Path::new("/logs/2015/08/15/23/elb.log"), false),
+                   None);
+        assert_eq!(super::path_time_window(Path::new("/logs/2015/misc/elb.log"), false), None);
+        assert_eq!(super::path_time_window(Path::new("/logs/2015/08/15"), false),
+                   super::path_time_window(Path::new("/logs/2015/08"), true));
+    }
+}
+
 #[cfg(test)]
 mod file_list_tests {
-    extern crate names;
     extern crate rand;
 
-    use std::{fs, panic, sync};
+    use std::fs;
     use std::io::Write;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
+    use chrono::{TimeZone, Utc};
+    use glob::Pattern;
+    use record_filters::TimeRange;
+    use test_common::{next_name, run_int_test_in_test_dir};
+    use super::FileDiscoveryConfig;
     use self::rand::distributions::{IndependentSample, Range};
 
     #[test]
@@ -283,21 +857,23 @@ mod file_list_tests {
                 let directory_depth = directory_depth_range.ind_sample(&mut thread_range);
                 let mut path = test_dir.to_owned();
                 for _ in 0..directory_depth {
-                    path.push_str(format!("/{}", NAME_GENERATOR.next()).as_str());
+                    path.push_str(format!("/{}", next_name()).as_str());
                     let _ = fs::create_dir(&path);
                 }
 
-                path.push_str(format!("/{}.log", NAME_GENERATOR.next()).as_str());
+                path.push_str(format!("/{}.log", next_name()).as_str());
                 let _ = fs::OpenOptions::new()
                     .read(true)
                     .write(true)
                     .create(true)
+                    .truncate(true)
                     .open(&path)
                     .unwrap()
-                    .write(&"test content".as_bytes());
+                    .write("test content".as_bytes());
             }
 
-            let files = super::file_list(Path::new(test_dir)).unwrap();
+            let files = super::file_list(Path::new(test_dir), &FileDiscoveryConfig::default())
+                .unwrap();
 
             assert_ne!(num_files, 0);
             assert_eq!(files.len(), num_files)
@@ -305,57 +881,222 @@ mod file_list_tests {
     }
 
     #[test]
-    fn file_list_should_return_0_when_there_are_no_files_in_the_directory() {
+    fn file_list_should_return_compressed_log_files() {
         run_int_test_in_test_dir(|test_dir| {
-            let files = super::file_list(Path::new(test_dir)).unwrap();
+            for filename in &["a.log", "b.log.gz", "c.log.bz2", "d.txt", "e.gz.tmp"] {
+                let _ = fs::File::create(format!("{}/{}", test_dir, filename));
+            }
 
-            assert_eq!(files.len(), 0)
+            let mut files = super::file_list(Path::new(test_dir), &FileDiscoveryConfig::default())
+                .unwrap();
+            files.sort();
+
+            let filenames = files.iter()
+                .map(|f| f.file_name().unwrap().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(filenames, vec!["a.log", "b.log.gz", "c.log.bz2"])
+        })
+    }
+
+    #[test]
+    fn file_list_should_keep_the_files_in_numeric_directories_below_the_date_directories() {
+        run_int_test_in_test_dir(|test_dir| {
+            fs::create_dir_all(format!("{}/2015/08/15/1234", test_dir)).unwrap();
+            let _ = fs::File::create(format!("{}/2015/08/15/1234/a.log", test_dir));
+            let august = TimeRange {
+                since: Some(Utc.with_ymd_and_hms(2015, 8, 1, 0, 0, 0).unwrap()),
+                until: Some(Utc.with_ymd_and_hms(2015, 9, 1, 0, 0, 0).unwrap()),
+            };
+
+            let discovery = FileDiscoveryConfig { time_range: august, ..Default::default() };
+            let files = super::file_list(Path::new(test_dir), &discovery).unwrap();
+
+            assert_eq!(files, vec![PathBuf::from(format!("{}/2015/08/15/1234/a.log", test_dir))])
         })
     }
 
-    pub fn run_int_test_in_test_dir<T>(test: T) -> ()
-        where T: FnOnce(&str) -> () + panic::UnwindSafe
-    {
-        creat_test_dir();
-        let test_dir_path = create_empty_dir();
+    #[test]
+    fn file_list_should_leave_out_the_files_and_directories_outside_of_the_time_range() {
+        run_int_test_in_test_dir(|test_dir| {
+            for dir in &["2015/07/31", "2015/08/15", "2015/09/01", "2014/12", "misc"] {
+                fs::create_dir_all(format!("{}/{}", test_dir, dir)).unwrap();
+            }
+            for filename in &["2015/07/31/a.log",
+                              "2015/08/15/b.log",
+                              "2015/09/01/c.log",
+                              "2014/12/d.log",
+                              "misc/e.log",
+                              "misc/1_elasticloadbalancing_us-east-1_elb_20150815T2345Z_1_x.log",
+                              "misc/1_elasticloadbalancing_us-east-1_elb_20151015T2345Z_1_x.log"] {
+                let _ = fs::File::create(format!("{}/{}", test_dir, filename));
+            }
+            let august = TimeRange {
+                since: Some(Utc.with_ymd_and_hms(2015, 8, 1, 0, 0, 0).unwrap()),
+                until: Some(Utc.with_ymd_and_hms(2015, 9, 1, 0, 0, 0).unwrap()),
+            };
 
-        let result = panic::catch_unwind(|| test(&test_dir_path));
+            let discovery = FileDiscoveryConfig { time_range: august, ..Default::default() };
+            let mut files = super::file_list(Path::new(test_dir), &discovery).unwrap();
+            files.sort();
 
-        let _ = fs::remove_dir_all(&test_dir_path);
+            let filenames = files.iter()
+                .map(|f| f.file_name().unwrap().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            // The last hours of July and the first hours of September are kept because their
+            // files can hold records from August.
+            assert_eq!(filenames,
+                       vec!["a.log",
+                            "b.log",
+                            "c.log",
+                            "1_elasticloadbalancing_us-east-1_elb_20150815T2345Z_1_x.log",
+                            "e.log"])
+        })
+    }
 
-        if let Err(err) = result {
-            panic::resume_unwind(err);
-        }
+    #[test]
+    fn file_list_should_apply_the_include_and_exclude_patterns() {
+        run_int_test_in_test_dir(|test_dir| {
+            for dir in &["prod/elb", "staging/elb", "prod/test-elb"] {
+                fs::create_dir_all(format!("{}/{}", test_dir, dir)).unwrap();
+            }
+            for filename in &["prod/elb/a.log", "staging/elb/b.log", "prod/test-elb/c.log",
+                              "prod/d.log"] {
+                let _ = fs::File::create(format!("{}/{}", test_dir, filename));
+            }
+            let discovery = FileDiscoveryConfig {
+                include: vec![Pattern::new("prod/**/*.log").unwrap(),
+                              Pattern::new("staging/**").unwrap()],
+                exclude: vec![Pattern::new("staging").unwrap(), Pattern::new("**/test-*").unwrap()],
+                ..Default::default()
+            };
+
+            let mut files = super::file_list(Path::new(test_dir), &discovery).unwrap();
+            files.sort();
+
+            let filenames = files.iter()
+                .map(|f| f.file_name().unwrap().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(filenames, vec!["d.log", "a.log"])
+        })
     }
 
-    pub const TEST_DIR: &'static str = "./int_tests";
-    static TEST_DIR_SYNC: sync::Once = sync::ONCE_INIT;
-    fn creat_test_dir() {
-        TEST_DIR_SYNC.call_once(|| { let _ = fs::create_dir(TEST_DIR); });
+    #[test]
+    fn file_list_should_return_the_files_having_the_configured_extensions() {
+        run_int_test_in_test_dir(|test_dir| {
+            for filename in &["a.log", "b.log.gz", "c.txt", "d.log.zst", "zst"] {
+                let _ = fs::File::create(format!("{}/{}", test_dir, filename));
+            }
+            let discovery = FileDiscoveryConfig {
+                extensions: vec!["txt".to_owned(), "log.zst".to_owned(), "zst".to_owned()],
+                ..Default::default()
+            };
+
+            let mut files = super::file_list(Path::new(test_dir), &discovery).unwrap();
+            files.sort();
+
+            let filenames = files.iter()
+                .map(|f| f.file_name().unwrap().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(filenames, vec!["c.txt", "d.log.zst"])
+        })
     }
 
-    fn create_empty_dir() -> String {
-        let path = format!("{}/{}", TEST_DIR, NAME_GENERATOR.next());
-        let _ = fs::create_dir(&path);
-        path
+    #[test]
+    fn file_list_should_stop_at_the_max_depth() {
+        run_int_test_in_test_dir(|test_dir| {
+            fs::create_dir_all(format!("{}/a/b", test_dir)).unwrap();
+            for filename in &["top.log", "a/middle.log", "a/b/bottom.log"] {
+                let _ = fs::File::create(format!("{}/{}", test_dir, filename));
+            }
+            let discovery = FileDiscoveryConfig { max_depth: Some(2), ..Default::default() };
+
+            let mut files = super::file_list(Path::new(test_dir), &discovery).unwrap();
+            files.sort();
+
+            let filenames = files.iter()
+                .map(|f| f.file_name().unwrap().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(filenames, vec!["middle.log", "top.log"])
+        })
     }
 
-    struct NameGenerator {
-        generator: Option<names::Generator<'static>>,
+    #[test]
+    #[cfg(unix)]
+    fn file_list_should_follow_symlinks_only_when_configured() {
+        run_int_test_in_test_dir(|test_dir| {
+            fs::create_dir_all(format!("{}/real", test_dir)).unwrap();
+            let _ = fs::File::create(format!("{}/real/a.log", test_dir));
+            let walk_root = format!("{}/root", test_dir);
+            fs::create_dir_all(&walk_root).unwrap();
+            ::std::os::unix::fs::symlink(fs::canonicalize(format!("{}/real", test_dir)).unwrap(),
+                                         format!("{}/linked", walk_root))
+                .unwrap();
+            let following = FileDiscoveryConfig { follow_links: true, ..Default::default() };
+
+            let not_followed = super::file_list(Path::new(&walk_root),
+                                                &FileDiscoveryConfig::default())
+                .unwrap();
+            let followed = super::file_list(Path::new(&walk_root), &following).unwrap();
+
+            assert_eq!(not_followed.len(), 0);
+            assert_eq!(followed.len(), 1)
+        })
     }
 
-    impl NameGenerator {
-        fn next(&mut self) -> String {
-            if self.generator.is_none() {
-                self.generator = Some(names::Generator::default());
+    #[test]
+    fn log_file_list_should_combine_the_locations_and_keep_the_files_and_stdin_as_given() {
+        run_int_test_in_test_dir(|test_dir| {
+            fs::create_dir_all(format!("{}/a", test_dir)).unwrap();
+            fs::create_dir_all(format!("{}/b", test_dir)).unwrap();
+            for filename in &["a/1.log", "a/2.txt", "b/3.log", "4.txt"] {
+                let _ = fs::File::create(format!("{}/{}", test_dir, filename));
             }
+            let locations = vec![PathBuf::from(format!("{}/a", test_dir)),
+                                 PathBuf::from(format!("{}/b", test_dir)),
+                                 PathBuf::from(format!("{}/4.txt", test_dir)),
+                                 PathBuf::from("-"),
+                                 PathBuf::from(format!("{}/b", test_dir)),
+                                 PathBuf::from("-")];
 
-            self.generator
-                .as_mut()
-                .map(|mut g| g.next().unwrap())
-                .unwrap()
-        }
+            let files = super::log_file_list(&locations, &FileDiscoveryConfig::default())
+                .unwrap();
+
+            let filenames = files.iter()
+                .map(|f| f.file_name().map_or("-", |f| f.to_str().unwrap()).to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(filenames, vec!["1.log", "3.log", "4.txt", "-"])
+        })
+    }
+
+    #[test]
+    fn log_file_list_should_return_an_error_when_a_location_is_missing() {
+        let locations = vec![PathBuf::from("./test_artifacts/missing")];
+
+        assert!(super::log_file_list(&locations, &FileDiscoveryConfig::default()).is_err())
     }
 
-    const NAME_GENERATOR: NameGenerator = NameGenerator { generator: None };
-}
\ No newline at end of file
+    #[test]
+    fn read_location_list_should_return_the_locations_on_the_non_blank_lines() {
+        run_int_test_in_test_dir(|test_dir| {
+            let list_path = format!("{}/files.txt", test_dir);
+            fs::write(&list_path, "a.log\n\n  b/c.log.gz \n-\n").unwrap();
+
+            let locations = super::read_location_list(Path::new(&list_path)).unwrap();
+
+            assert_eq!(locations,
+                       vec![PathBuf::from("a.log"),
+                            PathBuf::from("b/c.log.gz"),
+                            PathBuf::from("-")])
+        })
+    }
+
+    #[test]
+    fn file_list_should_return_0_when_there_are_no_files_in_the_directory() {
+        run_int_test_in_test_dir(|test_dir| {
+            let files = super::file_list(Path::new(test_dir), &FileDiscoveryConfig::default())
+                .unwrap();
+
+            assert_eq!(files.len(), 0)
+        })
+    }
+}
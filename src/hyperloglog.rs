@@ -0,0 +1,248 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// The number of hash bits that pick a register. 2^14 registers give a standard error of about
+// 0.8% in 16KB.
+const PRECISION: u32 = 14;
+const NUM_REGISTERS: usize = 1 << PRECISION;
+// A sparse HyperLogLog takes 4 bytes for each register that isn't empty, so it is switched to
+// the dense registers once it has half of their size.
+const MAX_SPARSE_REGISTERS: usize = NUM_REGISTERS / 8;
+
+#[derive(Clone, Debug)]
+enum Registers {
+    /// The index and rank of each register that isn't empty, sorted by index.
+    Sparse(Vec<(u16, u8)>),
+    Dense(Vec<u8>),
+}
+
+/// A HyperLogLog estimate of the number of distinct values it has seen.
+///
+/// HyperLogLogs built on different threads merge without losing accuracy, as long as they
+/// hash the same way, so every HyperLogLog uses std's DefaultHasher with its fixed keys.
+///
+/// Most aggregates only see a few clients, so the registers start out sparse and only take
+/// their full 16KB once many of them are in use. The estimate is the same either way.
+#[derive(Clone, Debug)]
+pub struct HyperLogLog {
+    registers: Registers,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> HyperLogLog {
+        HyperLogLog { registers: Registers::Sparse(Vec::new()) }
+    }
+}
+
+impl PartialEq for HyperLogLog {
+    fn eq(&self, other: &HyperLogLog) -> bool {
+        self.non_empty_registers() == other.non_empty_registers()
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert<T: Hash>(&mut self, value: &T) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // The marker bit caps the rank when all of the remaining bits are zero.
+        let remaining_bits = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = remaining_bits.leading_zeros() as u8 + 1;
+        self.raise_register(index, rank);
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        if let (Registers::Dense(registers), Registers::Dense(other_registers)) =
+            (&mut self.registers, &other.registers) {
+            for (register, other_register) in registers.iter_mut().zip(other_registers) {
+                if *other_register > *register {
+                    *register = *other_register;
+                }
+            }
+            return;
+        }
+        for (index, rank) in other.non_empty_registers() {
+            self.raise_register(index, rank);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let num_registers = NUM_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / num_registers);
+        let non_empty_registers = self.non_empty_registers();
+        let num_empty_registers = NUM_REGISTERS - non_empty_registers.len();
+        // Every empty register adds 2^-0 to the sum.
+        let inverse_sum: f64 = num_empty_registers as f64 +
+                               non_empty_registers.iter()
+            .map(|&(_, rank)| 2f64.powi(-i32::from(rank)))
+            .sum::<f64>();
+        let raw_estimate = alpha * num_registers * num_registers / inverse_sum;
+
+        // Linear counting is more accurate while many of the registers are empty.
+        if raw_estimate <= 2.5 * num_registers && num_empty_registers > 0 {
+            (num_registers * (num_registers / num_empty_registers as f64).ln()).round() as u64
+        } else {
+            raw_estimate.round() as u64
+        }
+    }
+
+    fn raise_register(&mut self, index: usize, rank: u8) {
+        let num_sparse_registers = match self.registers {
+            Registers::Sparse(ref mut registers) => {
+                match registers.binary_search_by_key(&(index as u16), |&(index, _)| index) {
+                    Ok(position) if rank > registers[position].1 => registers[position].1 = rank,
+                    Ok(_) => {}
+                    Err(position) => registers.insert(position, (index as u16, rank)),
+                }
+                registers.len()
+            }
+            Registers::Dense(ref mut registers) => {
+                if rank > registers[index] {
+                    registers[index] = rank;
+                }
+                return;
+            }
+        };
+        if num_sparse_registers > MAX_SPARSE_REGISTERS {
+            let mut registers = vec![0; NUM_REGISTERS];
+            for (index, rank) in self.non_empty_registers() {
+                registers[index] = rank;
+            }
+            self.registers = Registers::Dense(registers);
+        }
+    }
+
+    fn non_empty_registers(&self) -> Vec<(usize, u8)> {
+        match self.registers {
+            Registers::Sparse(ref registers) => {
+                registers.iter().map(|&(index, rank)| (index as usize, rank)).collect()
+            }
+            Registers::Dense(ref registers) => {
+                registers.iter()
+                    .enumerate()
+                    .filter(|&(_, rank)| *rank > 0)
+                    .map(|(index, rank)| (index, *rank))
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod hyperloglog_tests {
+
+    use std::net::{IpAddr, Ipv4Addr};
+    use super::{HyperLogLog, Registers};
+
+    fn client_address(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    fn assert_within(estimate: u64, expected: u64, relative_error: f64) {
+        let error = (estimate as f64 - expected as f64).abs() / expected as f64;
+        assert!(error <= relative_error,
+                "{} is not within {} of {}",
+                estimate,
+                relative_error,
+                expected);
+    }
+
+    #[test]
+    fn estimate_should_return_zero_when_nothing_was_inserted() {
+        assert_eq!(HyperLogLog::default().estimate(), 0)
+    }
+
+    #[test]
+    fn estimate_should_ignore_repeated_values() {
+        let mut hll = HyperLogLog::default();
+        for _ in 0..100 {
+            hll.insert(&client_address(1));
+            hll.insert(&client_address(2));
+        }
+
+        assert_eq!(hll.estimate(), 2)
+    }
+
+    #[test]
+    fn estimate_should_be_close_to_the_number_of_distinct_values() {
+        let mut small = HyperLogLog::default();
+        for n in 0..1000 {
+            small.insert(&client_address(n));
+        }
+        let mut large = HyperLogLog::default();
+        for n in 0..200_000 {
+            large.insert(&client_address(n));
+        }
+
+        assert_within(small.estimate(), 1000, 0.02);
+        assert_within(large.estimate(), 200_000, 0.03);
+    }
+
+    #[test]
+    fn merging_should_count_the_values_seen_by_either_hyperloglog_once() {
+        let mut all = HyperLogLog::default();
+        let mut first = HyperLogLog::default();
+        let mut second = HyperLogLog::default();
+        for n in 0..5000 {
+            all.insert(&client_address(n));
+            if n < 3000 {
+                first.insert(&client_address(n));
+            }
+            if n >= 2000 {
+                second.insert(&client_address(n));
+            }
+        }
+
+        first.merge(&second);
+
+        assert_eq!(first, all)
+    }
+
+    #[test]
+    fn hyperloglogs_should_stay_sparse_until_many_registers_are_in_use() {
+        let mut few = HyperLogLog::default();
+        for n in 0..100 {
+            few.insert(&client_address(n));
+        }
+        let mut many = HyperLogLog::default();
+        for n in 0..10_000 {
+            many.insert(&client_address(n));
+        }
+
+        assert!(match few.registers {
+            Registers::Sparse(ref registers) => registers.len() <= 100,
+            Registers::Dense(_) => false,
+        });
+        assert!(match many.registers {
+            Registers::Dense(ref registers) => registers.len() == super::NUM_REGISTERS,
+            Registers::Sparse(_) => false,
+        });
+        assert_within(few.estimate(), 100, 0.02);
+        assert_within(many.estimate(), 10_000, 0.02);
+    }
+
+    #[test]
+    fn merging_sparse_and_dense_hyperloglogs_should_match_inserting_every_value() {
+        let mut all = HyperLogLog::default();
+        let mut sparse = HyperLogLog::default();
+        let mut dense = HyperLogLog::default();
+        for n in 0..10_000 {
+            all.insert(&client_address(n));
+            if n < 50 {
+                sparse.insert(&client_address(n));
+            } else {
+                dense.insert(&client_address(n));
+            }
+        }
+        let mut sparse_into_dense = dense.clone();
+        sparse_into_dense.merge(&sparse);
+
+        sparse.merge(&dense);
+
+        assert_eq!(sparse, all);
+        assert_eq!(sparse_into_dense, all);
+        assert_eq!(sparse.estimate(), all.estimate())
+    }
+}
@@ -3,16 +3,30 @@ extern crate walkdir;
 extern crate log;
 extern crate elp;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate regex;
-#[macro_use]
-extern crate lazy_static;
 extern crate scoped_pool as sp;
 extern crate num_cpus;
+extern crate flate2;
+extern crate bzip2;
+extern crate percent_encoding;
+extern crate unicode_normalization;
+extern crate csv;
+extern crate sha2;
+extern crate glob;
+extern crate rusqlite;
+extern crate arrow_array;
+extern crate arrow_schema;
+extern crate parquet;
+#[cfg(test)]
+extern crate bytes;
+extern crate rustc_serialize;
 
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::error::Error;
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 #[macro_export]
 macro_rules! println_stderr(
@@ -27,17 +41,34 @@ macro_rules! println_stderr(
 pub mod file_handling;
 pub mod record_handling;
 pub mod aggregation_control;
+pub mod alb;
+pub mod quantile_sketch;
+pub mod hyperloglog;
+pub mod system_names;
+pub mod record_filters;
+pub mod output;
+pub mod sqlite_output;
+pub mod parquet_output;
 
-pub type ELBRecordAggregation = HashMap<record_handling::AggregateELBRecord, i64>;
+pub type ELBRecordAggregation = HashMap<record_handling::AggregateELBRecord,
+                                        record_handling::AggregateMetrics>;
+#[derive(Debug, Default, PartialEq)]
 pub struct FileAggregation {
     pub num_raw_records: usize,
     pub aggregation: ELBRecordAggregation,
+    pub file_formats: Vec<(PathBuf, record_handling::LogFormat)>,
+    pub system_name_variants: system_names::SystemNameVariants,
+    /// The number of requests carrying each token hash that isn't in the token registry.
+    pub unknown_tokens: HashMap<String, u64>,
+    /// The number of records each record filter rejected, in the order of the record filters.
+    pub rejected_records: Vec<u64>,
 }
 pub type CounterResult<'a> = Result<elp::ELBRecord<'a>, CounterError<'a>>;
 
 #[derive(Debug, PartialEq)]
 pub enum CounterError<'a> {
     RecordParsingErrors(elp::ParsingErrors<'a>),
+    ALBRecordParsingErrors(alb::ParsingErrors<'a>),
 }
 
 impl<'a> Display for CounterError<'a> {
@@ -46,6 +77,9 @@ impl<'a> Display for CounterError<'a> {
             CounterError::RecordParsingErrors(ref errs) => {
                 write!(f, "Parsing errors: {:?}.", errs.errors)
             }
+            CounterError::ALBRecordParsingErrors(ref errs) => {
+                write!(f, "Parsing errors: {:?}.", errs.errors)
+            }
         }
     }
 }
@@ -53,7 +87,8 @@ impl<'a> Display for CounterError<'a> {
 impl<'a> Error for CounterError<'a> {
     fn description(&self) -> &str {
         match *self {
-            CounterError::RecordParsingErrors(_) => "failed to parse record",
+            CounterError::RecordParsingErrors(_) |
+            CounterError::ALBRecordParsingErrors(_) => "failed to parse record",
         }
     }
 }
@@ -1,21 +1,42 @@
 extern crate rustc_serialize;
+extern crate csv;
 extern crate elp;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 extern crate clap;
 extern crate chrono;
+extern crate chrono_tz;
+extern crate regex;
+extern crate glob;
 #[macro_use]
 extern crate counter;
 extern crate num_cpus;
 extern crate scoped_pool as sp;
 
 use std::path::Path;
-use chrono::{DateTime, UTC};
+use std::collections::BTreeMap;
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use glob::Pattern;
 use counter::file_handling;
+use counter::file_handling::FileDiscoveryConfig;
+use counter::output;
+use counter::output::{OutputConfig, SortKey, OUTPUT_FORMAT_NAMES};
+use counter::sqlite_output;
+use counter::sqlite_output::RunMetadata;
+use counter::system_names::{ExtractionRule, SystemAliases, SystemNameNormalization,
+                            TokenRegistry, NORMALIZATION_STEP_NAMES};
 use counter::aggregation_control::AggregationController;
+use counter::record_filters;
+use counter::record_filters::{RecordFilter, TimeRange};
+use counter::record_handling::{AggregationConfig, Dimension, LogFormat, TimeBucket,
+                               DIMENSION_NAMES, LOG_FORMAT_NAMES, TIME_BUCKET_NAMES};
 use std::io::Write;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::path::PathBuf;
 
 const EXIT_SUCCESS: i32 = 0;
@@ -24,48 +45,150 @@ const EXIT_FAILURE: i32 = 1;
 fn main() {
     env_logger::init().unwrap();
     let runtime_context = RuntimeContext::new();
-    let log_location = runtime_context.log_location();
+    let log_locations = match runtime_context.log_locations() {
+        Ok(log_locations) => log_locations,
+        Err(e) => {
+            println_stderr!("The following error occurred while trying to get the log \
+                             locations. {}",
+                            e);
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
 
-    debug!("Running summary on {}.", log_location.to_str().unwrap());
+    debug!("Running summary on {:?}.", log_locations);
 
-    let start: Option<DateTime<UTC>> = if runtime_context.run_benchmark() {
-        Some(UTC::now())
+    let started_at = Utc::now();
+    let start: Option<DateTime<Utc>> = if runtime_context.run_benchmark() {
+        Some(started_at)
     } else {
         None
     };
 
-    let exit_code = match file_handling::file_list(log_location) {
+    let config = match runtime_context.aggregation_config() {
+        Ok(config) => config,
+        Err(e) => {
+            println_stderr!("The following error occurred while trying to configure the \
+                             aggregation. {}",
+                            e);
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
+
+    let output_config = match runtime_context.output_config(&config) {
+        Ok(output_config) => output_config,
+        Err(e) => {
+            println_stderr!("The following error occurred while trying to configure the \
+                             output. {}",
+                            e);
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
+
+    let discovery = match runtime_context.file_discovery_config() {
+        Ok(discovery) => discovery,
+        Err(e) => {
+            println_stderr!("The following error occurred while trying to configure the file \
+                             discovery. {}",
+                            e);
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
+
+    let exit_code = match file_handling::log_file_list(&log_locations, &discovery) {
         Ok(ref mut filenames) => {
             let num_files = filenames.len();
             debug!("Found {} files.", num_files);
 
+            if let Some(manifest_format) = runtime_context.dry_run() {
+                std::process::exit(write_manifest(filenames,
+                                                  config.log_format,
+                                                  manifest_format,
+                                                  output_config.path.as_deref()));
+            }
+
+            let record_filters = config.record_filters
+                .iter()
+                .map(|filter| filter.to_string())
+                .collect::<Vec<_>>();
+            let config = Arc::new(config);
             let mut runner = Runner::new();
-            let final_agg = runner.run(num_cpus::get(), filenames);
+            let final_agg = runner.run(num_cpus::get(), config.clone(), filenames);
 
             debug!("Processed {} records in {} files.",
             final_agg.num_raw_records,
             num_files);
 
-            for (aggregate, total) in &final_agg.aggregation {
-                println!("{},{},{},{}",
-                         aggregate.system_name,
-                         aggregate.day.format("%Y-%m-%d").to_string(),
-                         aggregate.client_address,
-                         total);
+            let mut exit_code = EXIT_SUCCESS;
+            if let Err(e) = output::write_output(&output_config, &config, &final_agg) {
+                println_stderr!("Failed to write the aggregates with error {}.", e);
+                exit_code = EXIT_FAILURE;
+            }
+            if let Some(database_path) = runtime_context.sqlite_path() {
+                let run = RunMetadata {
+                    started_at,
+                    log_locations: log_locations.clone(),
+                    num_files,
+                };
+                if let Err(e) = sqlite_output::write_sqlite(&database_path,
+                                                            &run,
+                                                            &output_config,
+                                                            &config,
+                                                            &final_agg) {
+                    println_stderr!("Failed to write the aggregates to {} with error {}.",
+                                    database_path.display(),
+                                    e);
+                    exit_code = EXIT_FAILURE;
+                }
+            }
+
+            // Diagnostics are reported on stderr so that they don't mix with the aggregates.
+            let mut merged_system_names = final_agg.system_name_variants
+                .iter()
+                .filter(|&(_, raw_system_names)| raw_system_names.len() > 1)
+                .collect::<Vec<_>>();
+            merged_system_names.sort_by_key(|&(system_name, _)| system_name);
+            for (system_name, raw_system_names) in merged_system_names {
+                let mut raw_system_names = raw_system_names.iter().collect::<Vec<_>>();
+                raw_system_names.sort();
+                println_stderr!("Merged {} raw system names into {}: {:?}.",
+                                raw_system_names.len(),
+                                system_name,
+                                raw_system_names);
+            }
+
+            for (index, filter) in record_filters.iter().enumerate() {
+                println_stderr!("The filter {} rejected {} records.",
+                                filter,
+                                final_agg.rejected_records.get(index).unwrap_or(&0));
+            }
+
+            let mut unknown_tokens = final_agg.unknown_tokens.iter().collect::<Vec<_>>();
+            unknown_tokens.sort_by(|&(hash_a, count_a), &(hash_b, count_b)| {
+                count_b.cmp(count_a).then(hash_a.cmp(hash_b))
+            });
+            for (token_hash, num_requests) in unknown_tokens {
+                println_stderr!("Unknown token {} was used by {} requests.",
+                                token_hash,
+                                num_requests);
             }
 
             if let Some(start_time) = start {
-                let end_time = UTC::now();
+                let end_time = Utc::now();
                 let time = end_time - start_time;
-                println!("Processed {} files having {} records in {} milliseconds and produced \
-                          {} aggregates.",
-                         num_files,
-                         final_agg.num_raw_records,
-                         time.num_milliseconds(),
-                         final_agg.aggregation.len());
+                println_stderr!("Processed {} files having {} records in {} milliseconds and \
+                                 produced {} aggregates.",
+                                num_files,
+                                final_agg.num_raw_records,
+                                time.num_milliseconds(),
+                                final_agg.aggregation.len());
+                let mut file_formats = final_agg.file_formats.clone();
+                file_formats.sort();
+                for (filename, log_format) in file_formats {
+                    println_stderr!("Read {} as {}.", filename.display(), log_format);
+                }
             }
             runner.shutdown();
-            EXIT_SUCCESS
+            exit_code
         }
 
         Err(e) => {
@@ -79,6 +202,90 @@ fn main() {
     std::process::exit(exit_code);
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ManifestFormat {
+    Csv,
+    Json,
+}
+
+const MANIFEST_FORMAT_NAMES: [&str; 2] = ["csv", "json"];
+
+// Writes what is known about each file to stdout, or to the output file when one is given,
+// without aggregating any records. Files that can't be read are reported on stderr and make the
+// dry run fail.
+fn write_manifest(filenames: &[PathBuf],
+                  log_format: Option<LogFormat>,
+                  manifest_format: ManifestFormat,
+                  path: Option<&Path>)
+                  -> i32 {
+    let mut exit_code = EXIT_SUCCESS;
+    let mut entries = Vec::new();
+    for filename in filenames {
+        match file_handling::manifest_entry(filename, log_format) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                println_stderr!("Failed to read file {} with error {}.", filename.display(), e);
+                exit_code = EXIT_FAILURE;
+            }
+        }
+    }
+
+    let write = |writer: &mut dyn Write| {
+        match manifest_format {
+            ManifestFormat::Csv => write_csv_manifest(writer, &entries),
+            ManifestFormat::Json => {
+                let rows = entries.iter().map(manifest_json).collect();
+                writeln!(writer, "{}", json::as_pretty_json(&Json::Array(rows)))
+            }
+        }
+    };
+    let result = match path {
+        Some(path) => output::write_atomically(path, |writer| write(writer)),
+        None => write(&mut std::io::stdout()),
+    };
+    if let Err(e) = result {
+        println_stderr!("Failed to write the manifest with error {}.", e);
+        exit_code = EXIT_FAILURE;
+    }
+    exit_code
+}
+
+fn manifest_time(modified: &DateTime<Utc>) -> String {
+    modified.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn manifest_fields(entry: &file_handling::ManifestEntry) -> [String; 4] {
+    [entry.path.display().to_string(),
+     entry.size.map_or(String::new(), |size| size.to_string()),
+     entry.modified.as_ref().map_or(String::new(), manifest_time),
+     entry.log_format.map_or(String::new(), |log_format| log_format.to_string())]
+}
+
+fn write_csv_manifest(writer: &mut dyn Write,
+                      entries: &[file_handling::ManifestEntry])
+                      -> std::io::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["path", "size_bytes", "modified", "format"])?;
+    for entry in entries {
+        writer.write_record(manifest_fields(entry))?;
+    }
+    writer.flush()
+}
+
+fn manifest_json(entry: &file_handling::ManifestEntry) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("path".to_owned(), Json::String(entry.path.display().to_string()));
+    object.insert("size_bytes".to_owned(), entry.size.map_or(Json::Null, Json::U64));
+    object.insert("modified".to_owned(),
+                  entry.modified
+                      .as_ref()
+                      .map_or(Json::Null, |modified| Json::String(manifest_time(modified))));
+    object.insert("format".to_owned(),
+                  entry.log_format
+                      .map_or(Json::Null, |log_format| Json::String(log_format.to_string())));
+    Json::Object(object)
+}
+
 struct Runner {
     thread_pool: sp::Pool,
     file_handling_msg_senders: Vec<mpsc::Sender<file_handling::FileHandlingMessages>>,
@@ -93,15 +300,20 @@ impl Runner {
         }
     }
 
-    fn run(&mut self, num_file_aggregators: usize, mut filenames: &mut Vec<PathBuf>) -> counter::FileAggregation {
+    fn run(&mut self,
+           num_file_aggregators: usize,
+           config: Arc<AggregationConfig>,
+           filenames: &mut Vec<PathBuf>)
+           -> counter::FileAggregation {
         let (agg_msg_sender, agg_msg_receiver) = mpsc::channel::<_>();
         for sender_id in 0..num_file_aggregators {
             let (file_handling_msg_sender, file_handling_msg_receiver) = mpsc::channel::<_>();
             self.file_handling_msg_senders.push(file_handling_msg_sender);
             let cloned_agg_msg_sender = agg_msg_sender.clone();
+            let cloned_config = config.clone();
             self.thread_pool.expand();
             self.thread_pool.spawn(move || {
-                file_handling::FileAggregator::new(sender_id)
+                file_handling::FileAggregator::new(sender_id, cloned_config)
                     .run(&file_handling_msg_receiver, &cloned_agg_msg_sender);
             });
         }
@@ -120,7 +332,7 @@ impl Runner {
         self.thread_pool.workers()
     }
 
-    fn shutdown(&mut self) -> () {
+    fn shutdown(&mut self) {
         // Shutting down the file handlers is a precaution to prevent hangs and aids in testing.
         for msg_sender in self.file_handling_msg_senders.iter() {
             let _ = msg_sender.send(counter::file_handling::FileHandlingMessages::Done);
@@ -129,8 +341,38 @@ impl Runner {
     }
 }
 
-const LOG_LOCATION_ARG: &'static str = "log-location";
-const BENCHMARK_ARG: &'static str = "benchmark";
+const LOG_LOCATION_ARG: &str = "log-location";
+const FILES_FROM_ARG: &str = "files-from";
+const DRY_RUN_ARG: &str = "dry-run";
+const MANIFEST_FORMAT_ARG: &str = "manifest-format";
+const OUTPUT_FORMAT_ARG: &str = "output-format";
+const SORT_BY_ARG: &str = "sort-by";
+const OUTPUT_ARG: &str = "output";
+const SQLITE_ARG: &str = "sqlite";
+const BENCHMARK_ARG: &str = "benchmark";
+const FORMAT_ARG: &str = "format";
+const GROUP_BY_ARG: &str = "group-by";
+const TIME_BUCKET_ARG: &str = "time-bucket";
+const TIME_ZONE_ARG: &str = "time-zone";
+const PERCENTILES_ARG: &str = "percentiles";
+const DISTINCT_CLIENTS_ARG: &str = "distinct-clients";
+const NORMALIZE_SYSTEM_NAMES_ARG: &str = "normalize-system-names";
+const SYSTEM_RULE_ARG: &str = "system-rule";
+const SYSTEM_ALIASES_ARG: &str = "system-aliases";
+const UNMAPPED_SYSTEM_ARG: &str = "unmapped-system";
+const UNDEFINED_SYSTEM_ARG: &str = "undefined-system";
+const TOKEN_REGISTRY_ARG: &str = "token-registry";
+const TOKEN_PARAM_ARG: &str = "token-param";
+const STATUS_ARG: &str = "status";
+const METHOD_ARG: &str = "method";
+const EXCLUDE_PATH_REGEX_ARG: &str = "exclude-path-regex";
+const SINCE_ARG: &str = "since";
+const UNTIL_ARG: &str = "until";
+const INCLUDE_ARG: &str = "include";
+const EXCLUDE_ARG: &str = "exclude";
+const EXTENSIONS_ARG: &str = "extensions";
+const FOLLOW_SYMLINKS_ARG: &str = "follow-symlinks";
+const MAX_DEPTH_ARG: &str = "max-depth";
 
 struct RuntimeContext<'a> {
     arg_matches: clap::ArgMatches<'a>,
@@ -140,7 +382,7 @@ impl<'a> RuntimeContext<'a> {
     fn new() -> RuntimeContext<'a> {
         let arg_matches = RuntimeContext::new_app().get_matches();
 
-        RuntimeContext { arg_matches: arg_matches }
+        RuntimeContext { arg_matches }
     }
 
     #[cfg(test)]
@@ -149,7 +391,7 @@ impl<'a> RuntimeContext<'a> {
             .get_matches_from_safe_borrow(args)
             .unwrap();
 
-        RuntimeContext { arg_matches: arg_matches }
+        RuntimeContext { arg_matches }
     }
 
     fn new_app<'b>() -> clap::App<'a, 'b> {
@@ -157,21 +399,464 @@ impl<'a> RuntimeContext<'a> {
             .version(include_str!("version.txt"))
             .version_short("v")
             .arg(clap::Arg::with_name(LOG_LOCATION_ARG)
-                .required(true)
-                .help("The root directory when the log files are stored."))
+                .required_unless(FILES_FROM_ARG)
+                .multiple(true)
+                .help("The root directories where the log files are stored, or log files. - \
+                       reads the records piped to stdin."))
+            .arg(clap::Arg::with_name(FILES_FROM_ARG)
+                .required(false)
+                .help("A file listing more log locations, one to a line.")
+                .long("files-from")
+                .takes_value(true))
+            .arg(clap::Arg::with_name(DRY_RUN_ARG)
+                .required(false)
+                .help("Write the path, size, modification time and format of each of the files \
+                       that would be read to stdout, or to the --output file, and exit without \
+                       aggregating them.")
+                .long("dry-run"))
+            .arg(clap::Arg::with_name(MANIFEST_FORMAT_ARG)
+                .required(false)
+                .help("The format of the dry run's file list. Defaults to csv.")
+                .long("manifest-format")
+                .takes_value(true)
+                .possible_values(&MANIFEST_FORMAT_NAMES)
+                .requires(DRY_RUN_ARG))
+            .arg(clap::Arg::with_name(OUTPUT_FORMAT_ARG)
+                .required(false)
+                .help("The format the aggregates are written in: csv for RFC 4180 CSV having a \
+                       header row, json for a JSON array of objects, ndjson for a JSON object \
+                       on each line or parquet for a Parquet file with a typed column for each \
+                       dimension and metric. Defaults to csv.")
+                .long("output-format")
+                .takes_value(true)
+                .possible_values(&OUTPUT_FORMAT_NAMES))
+            .arg(clap::Arg::with_name(SORT_BY_ARG)
+                .required(false)
+                .help("A comma separated list of what the aggregates are sorted by: any of the \
+                       dimensions they are grouped by, in ascending order, or requests, in \
+                       descending order. Ties are broken by the dimensions in the order they \
+                       are grouped by, which is also the default order.")
+                .long("sort-by")
+                .takes_value(true)
+                .use_delimiter(true)
+                .validator(|sort_key| sort_key.parse::<SortKey>().map(|_| ())))
+            .arg(clap::Arg::with_name(OUTPUT_ARG)
+                .required(false)
+                .help("The file the aggregates are written to instead of stdout. It is only \
+                       replaced once all of the aggregates have been written.")
+                .long("output")
+                .short("o")
+                .takes_value(true))
+            .arg(clap::Arg::with_name(SQLITE_ARG)
+                .required(false)
+                .help("A SQLite database the run and its aggregates are also added to. It is \
+                       created when it doesn't exist, and each run adds a row to its runs table \
+                       and its aggregates to its aggregates table under the run's run_id.")
+                .long("sqlite")
+                .takes_value(true))
             .arg(clap::Arg::with_name(BENCHMARK_ARG)
                 .required(false)
                 .help("Time the run and provide statistics at the end of the run.")
                 .long("benchmark")
                 .short("b"))
+            .arg(clap::Arg::with_name(FORMAT_ARG)
+                .required(false)
+                .help("The format of the log files. When it is not specified the format of each \
+                       file is detected from its first records.")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&LOG_FORMAT_NAMES))
+            .arg(clap::Arg::with_name(GROUP_BY_ARG)
+                .required(false)
+                .help("A comma separated list of the dimensions the records are counted by. \
+                       The output has a column for each dimension in the order given. The \
+                       resource is the numeric segment of the request path, such as a camera \
+                       id, and the rendition is the segment after it. Defaults to \
+                       system,time,client, or system,time with --distinct-clients.")
+                .long("group-by")
+                .takes_value(true)
+                .use_delimiter(true)
+                .possible_values(&DIMENSION_NAMES))
+            .arg(clap::Arg::with_name(TIME_BUCKET_ARG)
+                .required(false)
+                .help("The length of the periods the time dimension counts records over. \
+                       Defaults to day.")
+                .long("time-bucket")
+                .takes_value(true)
+                .possible_values(&TIME_BUCKET_NAMES))
+            .arg(clap::Arg::with_name(TIME_ZONE_ARG)
+                .required(false)
+                .help("The IANA time zone the time buckets start and end in, e.g. \
+                       America/New_York. When it is not UTC the start of each bucket is written \
+                       with its UTC offset. Defaults to UTC.")
+                .long("time-zone")
+                .takes_value(true)
+                .validator(|time_zone| {
+                    time_zone.parse::<Tz>().map(|_| ()).map_err(|err| err.to_string())
+                }))
+            .arg(clap::Arg::with_name(PERCENTILES_ARG)
+                .required(false)
+                .help("Add the p50, p95 and p99 of the backend processing time of each \
+                       aggregate to the output. They are accurate to within 1%.")
+                .long("percentiles"))
+            .arg(clap::Arg::with_name(DISTINCT_CLIENTS_ARG)
+                .required(false)
+                .help("Add an estimate of the number of distinct clients of each aggregate to \
+                       the output. The group by then defaults to system,time, giving the unique \
+                       clients of each system without a row per client.")
+                .long("distinct-clients"))
+            .arg(clap::Arg::with_name(NORMALIZE_SYSTEM_NAMES_ARG)
+                .required(false)
+                .help("A comma separated list of the steps applied to system names after they \
+                       are percent-decoded, or none. Defaults to case-fold,trim,nfc.")
+                .long("normalize-system-names")
+                .takes_value(true)
+                .use_delimiter(true)
+                .possible_values(&NORMALIZATION_STEP_NAMES)
+                .possible_value("none"))
+            .arg(clap::Arg::with_name(SYSTEM_RULE_ARG)
+                .required(false)
+                .help("A rule that finds the system name of a request. It is one of \
+                       param:NAME for a query parameter, path:INDEX for a path segment counting \
+                       from 0, regex:PATTERN for the capture group named system or \
+                       token:PARAM=FILE for the account a token registry, as read by \
+                       --token-registry, maps the token in a query parameter onto. Repeat it \
+                       to try several rules in order. Defaults to param:system.")
+                .long("system-rule")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(clap::Arg::with_name(SYSTEM_ALIASES_ARG)
+                .required(false)
+                .help("A CSV file having the header match,pattern,account that maps system \
+                       names onto the accounts they are reported under. The match is exact, \
+                       for a pattern that is a system name, or regex, for a pattern matched \
+                       against the normalized system names.")
+                .long("system-aliases")
+                .takes_value(true))
+            .arg(clap::Arg::with_name(UNMAPPED_SYSTEM_ARG)
+                .required(false)
+                .help("The name system names without an alias are reported under. By default \
+                       they are reported under their own name.")
+                .long("unmapped-system")
+                .takes_value(true)
+                .requires(SYSTEM_ALIASES_ARG))
+            .arg(clap::Arg::with_name(UNDEFINED_SYSTEM_ARG)
+                .required(false)
+                .help("The name records without a system name are reported under. Defaults to \
+                       UNDEFINED_SYSTEM.")
+                .long("undefined-system")
+                .takes_value(true))
+            .arg(clap::Arg::with_name(TOKEN_REGISTRY_ARG)
+                .required(false)
+                .help("A CSV file having the header token_hash,account, or a SQLite database \
+                       ending in .db, .sqlite or .sqlite3 having a tokens table with those \
+                       columns, that maps the SHA-256 hex digests of tokens onto accounts. \
+                       Requests carrying a registered token are reported under its account and \
+                       the requests carrying an unknown token are counted on stderr.")
+                .long("token-registry")
+                .takes_value(true))
+            .arg(clap::Arg::with_name(TOKEN_PARAM_ARG)
+                .required(false)
+                .help("The query parameter that carries the token. Defaults to pubtoken.")
+                .long("token-param")
+                .takes_value(true)
+                .requires(TOKEN_REGISTRY_ARG))
+            .arg(clap::Arg::with_name(STATUS_ARG)
+                .required(false)
+                .help("A comma separated list of the status codes, such as 200, or status \
+                       classes, such as 2xx, of the records that are counted. By default \
+                       records are counted whatever their status.")
+                .long("status")
+                .takes_value(true)
+                .use_delimiter(true))
+            .arg(clap::Arg::with_name(METHOD_ARG)
+                .required(false)
+                .help("A comma separated list of the request methods of the records that are \
+                       counted. By default records are counted whatever their method.")
+                .long("method")
+                .takes_value(true)
+                .use_delimiter(true))
+            .arg(clap::Arg::with_name(EXCLUDE_PATH_REGEX_ARG)
+                .required(false)
+                .help("A regex matched against the request paths of the records that are not \
+                       counted, such as ^/health. Repeat it to exclude several paths.")
+                .long("exclude-path-regex")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(clap::Arg::with_name(SINCE_ARG)
+                .required(false)
+                .help("Only count the records from this time on. It is an RFC 3339 timestamp \
+                       or a YYYY-MM-DD date, which starts at midnight in the time zone. Log \
+                       files and YYYY/MM/DD directories that end before it are not read.")
+                .long("since")
+                .takes_value(true))
+            .arg(clap::Arg::with_name(UNTIL_ARG)
+                .required(false)
+                .help("Only count the records before this time. It is an RFC 3339 timestamp \
+                       or a YYYY-MM-DD date, which starts at midnight in the time zone. Log \
+                       files and YYYY/MM/DD directories that start after it are not read.")
+                .long("until")
+                .takes_value(true))
+            .arg(clap::Arg::with_name(INCLUDE_ARG)
+                .required(false)
+                .help("A glob matched against the paths of the files relative to the log \
+                       location. When it is given only the matching files are read. * doesn't \
+                       match a /, so use ** to match across directories. Repeat it to include \
+                       several patterns.")
+                .long("include")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(clap::Arg::with_name(EXCLUDE_ARG)
+                .required(false)
+                .help("A glob matched against the paths of the files and directories relative \
+                       to the log location. Matching files are not read and matching \
+                       directories are not walked. Repeat it to exclude several patterns.")
+                .long("exclude")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(clap::Arg::with_name(EXTENSIONS_ARG)
+                .required(false)
+                .help("A comma separated list of the suffixes of the files that are read, \
+                       such as log,gz,log.zst. Defaults to log,gz,bz2.")
+                .long("extensions")
+                .takes_value(true)
+                .use_delimiter(true))
+            .arg(clap::Arg::with_name(FOLLOW_SYMLINKS_ARG)
+                .required(false)
+                .help("Follow symbolic links while walking the log location.")
+                .long("follow-symlinks"))
+            .arg(clap::Arg::with_name(MAX_DEPTH_ARG)
+                .required(false)
+                .help("The number of directory levels below the log location that are walked. \
+                       1 only reads the files directly in the log location.")
+                .long("max-depth")
+                .takes_value(true)
+                .validator(|max_depth| {
+                    max_depth.parse::<usize>().map(|_| ()).map_err(|err| err.to_string())
+                }))
     }
 
     fn run_benchmark(&self) -> bool {
         self.arg_matches.is_present(BENCHMARK_ARG)
     }
 
-    fn log_location(&self) -> &Path {
-        Path::new(self.arg_matches.value_of(LOG_LOCATION_ARG).unwrap())
+    fn dry_run(&self) -> Option<ManifestFormat> {
+        if !self.arg_matches.is_present(DRY_RUN_ARG) {
+            return None;
+        }
+        match self.arg_matches.value_of(MANIFEST_FORMAT_ARG) {
+            Some("json") => Some(ManifestFormat::Json),
+            _ => Some(ManifestFormat::Csv),
+        }
+    }
+
+    fn output_config(&self, config: &AggregationConfig) -> Result<OutputConfig, String> {
+        let defaults = OutputConfig::default();
+        // clap has already validated the values.
+        let output_config = OutputConfig {
+            format: self.arg_matches
+                .value_of(OUTPUT_FORMAT_ARG)
+                .map(|output_format| output_format.parse().unwrap())
+                .unwrap_or(defaults.format),
+            path: self.arg_matches.value_of(OUTPUT_ARG).map(PathBuf::from),
+            sort_by: self.arg_matches
+                .values_of(SORT_BY_ARG)
+                .map(|sort_keys| sort_keys.map(|sort_key| sort_key.parse().unwrap()).collect())
+                .unwrap_or(defaults.sort_by),
+        };
+        output_config.check_sort_keys(config)?;
+        Ok(output_config)
+    }
+
+    fn sqlite_path(&self) -> Option<PathBuf> {
+        self.arg_matches.value_of(SQLITE_ARG).map(PathBuf::from)
+    }
+
+    fn log_format(&self) -> Option<LogFormat> {
+        // clap has already checked the value against the possible values.
+        self.arg_matches.value_of(FORMAT_ARG).map(|format| format.parse().unwrap())
+    }
+
+    fn group_by(&self) -> Vec<Dimension> {
+        match self.arg_matches.values_of(GROUP_BY_ARG) {
+            Some(dimensions) => dimensions.map(|dimension| dimension.parse().unwrap()).collect(),
+            None if self.distinct_clients() => vec![Dimension::System, Dimension::Time],
+            None => AggregationConfig::default().group_by,
+        }
+    }
+
+    fn distinct_clients(&self) -> bool {
+        self.arg_matches.is_present(DISTINCT_CLIENTS_ARG)
+    }
+
+    fn time_bucket(&self) -> TimeBucket {
+        self.arg_matches
+            .value_of(TIME_BUCKET_ARG)
+            .map(|time_bucket| time_bucket.parse().unwrap())
+            .unwrap_or(TimeBucket::Day)
+    }
+
+    fn backend_processing_time_percentiles(&self) -> bool {
+        self.arg_matches.is_present(PERCENTILES_ARG)
+    }
+
+    fn system_name_normalization(&self) -> SystemNameNormalization {
+        match self.arg_matches.values_of(NORMALIZE_SYSTEM_NAMES_ARG) {
+            Some(steps) => {
+                let mut normalization = SystemNameNormalization::none();
+                for step in steps.filter(|step| *step != "none") {
+                    normalization.enable(step.parse().unwrap());
+                }
+                normalization
+            }
+            None => SystemNameNormalization::default(),
+        }
+    }
+
+    fn time_zone(&self) -> Tz {
+        // clap has already validated the value.
+        self.arg_matches
+            .value_of(TIME_ZONE_ARG)
+            .map(|time_zone| time_zone.parse().unwrap())
+            .unwrap_or(Tz::UTC)
+    }
+
+    fn system_aliases(&self,
+                      normalization: &SystemNameNormalization)
+                      -> Result<Option<SystemAliases>, String> {
+        match self.arg_matches.value_of(SYSTEM_ALIASES_ARG) {
+            Some(path) => {
+                SystemAliases::from_file(Path::new(path), normalization)
+                    .map(Some)
+                    .map_err(|err| format!("Failed to read the system aliases {}: {}", path, err))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn token_registry(&self) -> Result<Option<TokenRegistry>, String> {
+        match self.arg_matches.value_of(TOKEN_REGISTRY_ARG) {
+            Some(path) => {
+                let param = self.arg_matches.value_of(TOKEN_PARAM_ARG).unwrap_or("pubtoken");
+                TokenRegistry::from_file(Path::new(path), param)
+                    .map(Some)
+                    .map_err(|err| format!("Failed to read the token registry {}: {}", path, err))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn record_filters(&self) -> Result<Vec<RecordFilter>, String> {
+        let mut record_filters = Vec::new();
+        if let Some(patterns) = self.arg_matches.values_of(STATUS_ARG) {
+            record_filters.push(RecordFilter::Status(patterns.map(|pattern| pattern.parse())
+                .collect::<Result<_, _>>()?));
+        }
+        if let Some(methods) = self.arg_matches.values_of(METHOD_ARG) {
+            record_filters.push(RecordFilter::Method(methods.map(str::to_owned).collect()));
+        }
+        if let Some(regexes) = self.arg_matches.values_of(EXCLUDE_PATH_REGEX_ARG) {
+            for regex in regexes {
+                let regex = Regex::new(regex)
+                    .map_err(|err| format!("{} is not a valid regex: {}", regex, err))?;
+                record_filters.push(RecordFilter::ExcludePath(regex));
+            }
+        }
+        let time_range = self.time_range()?;
+        if !time_range.is_unbounded() {
+            record_filters.push(RecordFilter::Time(time_range));
+        }
+        Ok(record_filters)
+    }
+
+    fn time_range(&self) -> Result<TimeRange, String> {
+        let time_zone = self.time_zone();
+        let time_bound = |arg| {
+            self.arg_matches
+                .value_of(arg)
+                .map(|bound| record_filters::parse_time_bound(bound, &time_zone))
+                .map_or(Ok(None), |bound| bound.map(Some))
+        };
+        Ok(TimeRange {
+            since: time_bound(SINCE_ARG)?,
+            until: time_bound(UNTIL_ARG)?,
+        })
+    }
+
+    fn glob_patterns(&self, arg: &str) -> Result<Vec<Pattern>, String> {
+        self.arg_matches
+            .values_of(arg)
+            .map_or(Ok(Vec::new()), |patterns| {
+                patterns.map(|pattern| {
+                        Pattern::new(pattern)
+                            .map_err(|err| format!("{} is not a valid glob: {}", pattern, err))
+                    })
+                    .collect()
+            })
+    }
+
+    fn file_discovery_config(&self) -> Result<FileDiscoveryConfig, String> {
+        let defaults = FileDiscoveryConfig::default();
+        Ok(FileDiscoveryConfig {
+            time_range: self.time_range()?,
+            include: self.glob_patterns(INCLUDE_ARG)?,
+            exclude: self.glob_patterns(EXCLUDE_ARG)?,
+            extensions: self.arg_matches
+                .values_of(EXTENSIONS_ARG)
+                .map(|extensions| {
+                    extensions.map(|ext| ext.trim_start_matches('.').to_owned()).collect()
+                })
+                .unwrap_or(defaults.extensions),
+            follow_links: self.arg_matches.is_present(FOLLOW_SYMLINKS_ARG),
+            // clap has already validated the value.
+            max_depth: self.arg_matches.value_of(MAX_DEPTH_ARG).map(|depth| depth.parse().unwrap()),
+        })
+    }
+
+    fn system_extraction(&self) -> Result<Vec<ExtractionRule>, String> {
+        match self.arg_matches.values_of(SYSTEM_RULE_ARG) {
+            Some(rules) => rules.map(|rule| rule.parse()).collect(),
+            None => Ok(AggregationConfig::default().system_extraction),
+        }
+    }
+
+    fn aggregation_config(&self) -> Result<AggregationConfig, String> {
+        let system_name_normalization = self.system_name_normalization();
+        let defaults = AggregationConfig::default();
+        Ok(AggregationConfig {
+            log_format: self.log_format(),
+            group_by: self.group_by(),
+            time_bucket: self.time_bucket(),
+            time_zone: self.time_zone(),
+            backend_processing_time_percentiles: self.backend_processing_time_percentiles(),
+            distinct_clients: self.distinct_clients(),
+            record_filters: self.record_filters()?,
+            token_registry: self.token_registry()?,
+            system_extraction: self.system_extraction()?,
+            system_aliases: self.system_aliases(&system_name_normalization)?,
+            system_name_normalization,
+            unmapped_system: self.arg_matches.value_of(UNMAPPED_SYSTEM_ARG).map(str::to_owned),
+            undefined_system: self.arg_matches
+                .value_of(UNDEFINED_SYSTEM_ARG)
+                .map(str::to_owned)
+                .unwrap_or(defaults.undefined_system),
+        })
+    }
+
+    fn log_locations(&self) -> Result<Vec<PathBuf>, String> {
+        let mut log_locations = self.arg_matches
+            .values_of(LOG_LOCATION_ARG)
+            .map_or(Vec::new(), |locations| locations.map(PathBuf::from).collect());
+        if let Some(files_from) = self.arg_matches.value_of(FILES_FROM_ARG) {
+            let listed_locations = file_handling::read_location_list(Path::new(files_from))
+                .map_err(|err| format!("Failed to read the file list {}: {}", files_from, err))?;
+            log_locations.extend(listed_locations);
+        }
+        Ok(log_locations)
     }
 }
 
@@ -180,15 +865,18 @@ mod full_run_tests {
 
     use std::path::PathBuf;
     use counter::file_handling;
+    use counter::file_handling::FileDiscoveryConfig;
+    use std::sync::Arc;
 
     #[test]
     #[ignore]
     fn a_full_run_should_return_the_correct_aggregation_results() {
         let num_cpus = ::num_cpus::get();
-        let mut files = file_handling::file_list(&PathBuf::from("./test_artifacts/log_files")).unwrap();
+        let mut files = file_handling::file_list(&PathBuf::from("./test_artifacts/log_files"),
+                                                 &FileDiscoveryConfig::default()).unwrap();
         let mut runner = super::Runner::new();
 
-        let file_agg = runner.run(num_cpus, &mut files);
+        let file_agg = runner.run(num_cpus, Arc::default(), &mut files);
 
         assert_eq!(file_agg.num_raw_records, 838140);
         assert_eq!(file_agg.aggregation.len(), 95479);
@@ -199,6 +887,7 @@ mod full_run_tests {
 mod runner_tests {
 
     use std::path::PathBuf;
+    use std::sync::Arc;
 
     #[test]
     fn runner_should_create_the_same_number_of_file_handling_message_senders_as_host_cpus() {
@@ -207,7 +896,7 @@ mod runner_tests {
         files.push(PathBuf::from("./test_artifacts/test_elb_log_file.log"));
         let mut runner = super::Runner::new();
 
-        let _ = runner.run(num_cpus, &mut files);
+        let _ = runner.run(num_cpus, Arc::default(), &mut files);
 
         assert_eq!(runner.num_file_handling_msg_senders(), num_cpus);
 
@@ -221,7 +910,7 @@ mod runner_tests {
         files.push(PathBuf::from("./test_artifacts/test_elb_log_file.log"));
         let mut runner = super::Runner::new();
 
-        let _ = runner.run(num_cpus, &mut files);
+        let _ = runner.run(num_cpus, Arc::default(), &mut files);
 
         assert_eq!(runner.num_threads_in_pool(), num_cpus);
 
@@ -233,14 +922,49 @@ mod runner_tests {
 mod runtime_context_tests {
     use super::*;
     use std::panic;
+    use chrono::TimeZone;
+    use counter::output::OutputFormat;
+    use counter::system_names::hash_token;
 
     #[test]
-    fn log_location_should_return_the_specified_value() {
+    fn log_locations_should_return_the_specified_value() {
         let arg_vec = vec!["counter", "~/logs"];
 
         let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
 
-        assert_eq!(runtime_context.log_location().to_str().unwrap(), "~/logs")
+        assert_eq!(runtime_context.log_locations(), Ok(vec![PathBuf::from("~/logs")]))
+    }
+
+    #[test]
+    fn log_locations_should_return_every_location_including_stdin() {
+        let arg_vec = vec!["counter", "~/logs", "-", "/var/log/elb"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.log_locations(),
+                   Ok(vec![PathBuf::from("~/logs"),
+                           PathBuf::from("-"),
+                           PathBuf::from("/var/log/elb")]))
+    }
+
+    #[test]
+    fn log_locations_should_add_the_locations_listed_in_the_files_from_file() {
+        let arg_vec = vec!["counter", "--files-from", "./test_artifacts/test_location_list.txt"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.log_locations(),
+                   Ok(vec![PathBuf::from("./test_artifacts/test_elb_log_file.log"),
+                           PathBuf::from("./test_artifacts/test_alb_log_file.log")]))
+    }
+
+    #[test]
+    fn log_locations_should_return_an_error_when_the_files_from_file_is_missing() {
+        let arg_vec = vec!["counter", "--files-from", "./test_artifacts/missing.txt"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.log_locations().is_err())
     }
 
     #[test]
@@ -267,7 +991,7 @@ mod runtime_context_tests {
 
         let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
 
-        assert_eq!(runtime_context.run_benchmark(), false)
+        assert!(!runtime_context.run_benchmark())
     }
 
     #[test]
@@ -287,4 +1011,526 @@ mod runtime_context_tests {
 
         assert!(runtime_context.run_benchmark())
     }
+
+    #[test]
+    fn dry_run_should_return_none_when_dry_run_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.dry_run(), None)
+    }
+
+    #[test]
+    fn dry_run_should_return_the_manifest_format_when_dry_run_arg_is_set() {
+        let csv_args = vec!["counter", "--dry-run", "~/logs"];
+        let json_args = vec!["counter", "--dry-run", "--manifest-format", "json", "~/logs"];
+
+        assert_eq!(RuntimeContext::new_test_runtime_context(csv_args).dry_run(),
+                   Some(ManifestFormat::Csv));
+        assert_eq!(RuntimeContext::new_test_runtime_context(json_args).dry_run(),
+                   Some(ManifestFormat::Json))
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_manifest_format_is_set_without_dry_run() {
+        let arg_vec = vec!["counter", "--manifest-format", "json", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn write_manifest_should_write_to_the_output_file_when_one_is_given() {
+        let path = std::env::temp_dir().join(format!("counter_manifest_{}.csv",
+                                                     std::process::id()));
+
+        let exit_code = write_manifest(&[PathBuf::from("-")],
+                                       None,
+                                       ManifestFormat::Csv,
+                                       Some(path.as_path()));
+
+        let written = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(exit_code, EXIT_SUCCESS);
+        assert_eq!(written.unwrap(), "path,size_bytes,modified,format\n-,,,\n")
+    }
+
+    #[test]
+    fn manifest_json_should_hold_null_for_the_unknown_fields_of_stdin() {
+        let entry = file_handling::manifest_entry(Path::new("-"), None).unwrap();
+
+        assert_eq!(manifest_json(&entry).to_string(),
+                   r#"{"format":null,"modified":null,"path":"-","size_bytes":null}"#)
+    }
+
+    #[test]
+    fn sqlite_path_should_return_the_specified_database() {
+        let arg_vec = vec!["counter", "--sqlite", "billing.db", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.sqlite_path(), Some(PathBuf::from("billing.db")))
+    }
+
+    #[test]
+    fn output_config_should_return_the_defaults_when_the_output_args_are_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_config(&AggregationConfig::default()),
+                   Ok(OutputConfig::default()))
+    }
+
+    #[test]
+    fn output_config_should_return_the_specified_format_and_sort_keys() {
+        let arg_vec = vec!["counter",
+                           "--output-format",
+                           "ndjson",
+                           "--sort-by",
+                           "requests,system",
+                           "--output",
+                           "report.ndjson",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_config(&AggregationConfig::default()),
+                   Ok(OutputConfig {
+                       format: OutputFormat::Ndjson,
+                       path: Some(PathBuf::from("report.ndjson")),
+                       sort_by: vec![SortKey::Requests, SortKey::Dimension(Dimension::System)],
+                   }))
+    }
+
+    #[test]
+    fn output_config_should_return_an_error_when_sorting_by_a_dimension_not_grouped_by() {
+        let arg_vec = vec!["counter", "--sort-by", "backend", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.output_config(&AggregationConfig::default()).is_err())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_a_sort_key_is_unknown() {
+        let arg_vec = vec!["counter", "--sort-by", "day", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn log_format_should_return_none_when_format_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.log_format(), None)
+    }
+
+    #[test]
+    fn log_format_should_return_the_specified_format() {
+        let arg_vec = vec!["counter", "--format", "alb", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.log_format(), Some(LogFormat::ALB))
+    }
+
+    #[test]
+    fn group_by_should_return_the_default_dimensions_when_group_by_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.group_by(),
+                   vec![Dimension::System, Dimension::Time, Dimension::Client])
+    }
+
+    #[test]
+    fn group_by_should_return_the_specified_dimensions_in_order() {
+        let arg_vec = vec!["counter", "--group-by", "backend,system", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.group_by(),
+                   vec![Dimension::Backend, Dimension::System])
+    }
+
+    #[test]
+    fn time_bucket_should_return_day_when_time_bucket_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.time_bucket(), TimeBucket::Day)
+    }
+
+    #[test]
+    fn time_bucket_should_return_the_specified_time_bucket() {
+        let arg_vec = vec!["counter", "--time-bucket", "hour", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.time_bucket(), TimeBucket::Hour)
+    }
+
+    #[test]
+    fn time_zone_should_return_utc_when_time_zone_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.time_zone(), Tz::UTC)
+    }
+
+    #[test]
+    fn time_zone_should_return_the_specified_time_zone() {
+        let arg_vec = vec!["counter", "--time-zone", "America/New_York", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.time_zone(), Tz::America__New_York)
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_time_zone_is_unknown() {
+        let arg_vec = vec!["counter", "--time-zone", "America/Gotham", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn backend_processing_time_percentiles_should_return_false_when_percentiles_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(!runtime_context.backend_processing_time_percentiles())
+    }
+
+    #[test]
+    fn backend_processing_time_percentiles_should_return_true_when_percentiles_arg_is_set() {
+        let arg_vec = vec!["counter", "--percentiles", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.backend_processing_time_percentiles())
+    }
+
+    #[test]
+    fn group_by_should_leave_out_the_client_when_distinct_clients_arg_is_set() {
+        let arg_vec = vec!["counter", "--distinct-clients", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.distinct_clients());
+        assert_eq!(runtime_context.group_by(), vec![Dimension::System, Dimension::Time])
+    }
+
+    #[test]
+    fn group_by_should_return_the_specified_dimensions_when_distinct_clients_arg_is_set() {
+        let arg_vec = vec!["counter", "--distinct-clients", "--group-by", "system", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.group_by(), vec![Dimension::System])
+    }
+
+    #[test]
+    fn system_name_normalization_should_apply_every_step_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.system_name_normalization(),
+                   SystemNameNormalization::default())
+    }
+
+    #[test]
+    fn system_name_normalization_should_apply_only_the_specified_steps() {
+        let arg_vec = vec!["counter", "--normalize-system-names", "trim,nfc", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.system_name_normalization(),
+                   SystemNameNormalization {
+                       case_fold: false,
+                       ..SystemNameNormalization::default()
+                   })
+    }
+
+    #[test]
+    fn system_name_normalization_should_apply_no_steps_when_none_is_specified() {
+        let arg_vec = vec!["counter", "--normalize-system-names", "none", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.system_name_normalization(),
+                   SystemNameNormalization::none())
+    }
+
+    #[test]
+    fn aggregation_config_should_load_the_system_aliases_when_the_arg_is_set() {
+        let arg_vec = vec!["counter",
+                           "--system-aliases",
+                           "./test_artifacts/test_system_aliases.csv",
+                           "--unmapped-system",
+                           "UNMAPPED",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let config = runtime_context.aggregation_config().unwrap();
+
+        assert_eq!(config.system_aliases.unwrap().account("wpix 2"), Some("WPIX"));
+        assert_eq!(config.unmapped_system, Some("UNMAPPED".to_owned()));
+        assert_eq!(config.undefined_system, "UNDEFINED_SYSTEM")
+    }
+
+    #[test]
+    fn aggregation_config_should_return_an_error_when_the_system_aliases_are_bad() {
+        let arg_vec = vec!["counter",
+                           "--system-aliases",
+                           "./test_artifacts/test_bad_system_aliases.csv",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.aggregation_config().is_err())
+    }
+
+    #[test]
+    fn aggregation_config_should_load_the_token_registry_when_the_arg_is_set() {
+        let arg_vec = vec!["counter",
+                           "--token-registry",
+                           "./test_artifacts/test_token_registry.csv",
+                           "--token-param",
+                           "token",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let token_registry = runtime_context.aggregation_config().unwrap().token_registry.unwrap();
+
+        assert_eq!(token_registry.param, "token");
+        assert_eq!(token_registry.account(&hash_token("abc123")), Some("WPIX"));
+    }
+
+    #[test]
+    fn aggregation_config_should_return_an_error_when_the_token_registry_is_missing() {
+        let arg_vec = vec!["counter", "--token-registry", "./test_artifacts/missing.csv", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.aggregation_config().is_err())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_token_param_is_set_without_a_registry() {
+        let arg_vec = vec!["counter", "--token-param", "token", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn record_filters_should_return_no_filters_when_the_filter_args_are_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.record_filters().unwrap().is_empty())
+    }
+
+    #[test]
+    fn record_filters_should_return_a_filter_for_each_filter_arg() {
+        let arg_vec = vec!["counter",
+                           "--status",
+                           "2xx,304",
+                           "--method",
+                           "GET",
+                           "--exclude-path-regex",
+                           "^/health",
+                           "--exclude-path-regex",
+                           "^/status",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let record_filters = runtime_context.record_filters()
+            .unwrap()
+            .iter()
+            .map(|filter| filter.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(record_filters,
+                   vec!["status 2xx,304",
+                        "method GET",
+                        "exclude-path-regex ^/health",
+                        "exclude-path-regex ^/status"])
+    }
+
+    #[test]
+    fn record_filters_should_return_an_error_for_a_bad_status_or_regex() {
+        let bad_status = vec!["counter", "--status", "2yy", "~/logs"];
+        let bad_regex = vec!["counter", "--exclude-path-regex", "(", "~/logs"];
+
+        assert!(RuntimeContext::new_test_runtime_context(bad_status).record_filters().is_err());
+        assert!(RuntimeContext::new_test_runtime_context(bad_regex).record_filters().is_err())
+    }
+
+    #[test]
+    fn time_range_should_be_unbounded_when_since_and_until_are_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.time_range(), Ok(TimeRange::default()))
+    }
+
+    #[test]
+    fn time_range_should_start_dates_at_midnight_in_the_time_zone() {
+        let arg_vec = vec!["counter",
+                           "--since",
+                           "2015-08-01",
+                           "--until",
+                           "2015-09-01T00:00:00Z",
+                           "--time-zone",
+                           "America/New_York",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.time_range(),
+                   Ok(TimeRange {
+                       since: Some(Utc.with_ymd_and_hms(2015, 8, 1, 4, 0, 0).unwrap()),
+                       until: Some(Utc.with_ymd_and_hms(2015, 9, 1, 0, 0, 0).unwrap()),
+                   }));
+        assert_eq!(runtime_context.record_filters().unwrap().len(), 1)
+    }
+
+    #[test]
+    fn aggregation_config_should_return_an_error_for_a_bad_time_bound() {
+        let arg_vec = vec!["counter", "--since", "last month", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.aggregation_config().is_err())
+    }
+
+    #[test]
+    fn file_discovery_config_should_return_the_defaults_when_the_args_are_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let discovery = runtime_context.file_discovery_config().unwrap();
+
+        assert!(discovery.include.is_empty());
+        assert!(discovery.exclude.is_empty());
+        assert_eq!(discovery.extensions, vec!["log", "gz", "bz2"]);
+        assert!(!discovery.follow_links);
+        assert_eq!(discovery.max_depth, None)
+    }
+
+    #[test]
+    fn file_discovery_config_should_return_the_specified_values() {
+        let arg_vec = vec!["counter",
+                           "--include",
+                           "prod/**",
+                           "--exclude",
+                           "**/staging",
+                           "--exclude",
+                           "**/test-*",
+                           "--extensions",
+                           ".log,log.zst",
+                           "--follow-symlinks",
+                           "--max-depth",
+                           "3",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let discovery = runtime_context.file_discovery_config().unwrap();
+
+        assert_eq!(discovery.include, vec![Pattern::new("prod/**").unwrap()]);
+        assert_eq!(discovery.exclude.len(), 2);
+        assert_eq!(discovery.extensions, vec!["log", "log.zst"]);
+        assert!(discovery.follow_links);
+        assert_eq!(discovery.max_depth, Some(3))
+    }
+
+    #[test]
+    fn file_discovery_config_should_return_an_error_for_a_bad_glob() {
+        let arg_vec = vec!["counter", "--exclude", "***", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.file_discovery_config().is_err())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_max_depth_is_not_a_number() {
+        let arg_vec = vec!["counter", "--max-depth", "deep", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn system_extraction_should_return_the_rules_in_the_order_given() {
+        let arg_vec = vec!["counter",
+                           "--system-rule",
+                           "param:account",
+                           "--system-rule",
+                           "path:0",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let rules = runtime_context.system_extraction().unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].extract("/wpix/full?account=WPIX"), Some("WPIX"));
+        assert_eq!(rules[1].extract("/wpix/full?account=WPIX"), Some("wpix"));
+    }
+
+    #[test]
+    fn system_extraction_should_return_an_error_for_a_bad_rule() {
+        let arg_vec = vec!["counter", "--system-rule", "header:system", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.system_extraction().is_err())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_unmapped_system_is_set_without_aliases() {
+        let arg_vec = vec!["counter", "--unmapped-system", "UNMAPPED", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_a_dimension_is_unknown() {
+        let arg_vec = vec!["counter", "--group-by", "system,camera", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_format_is_unknown() {
+        let arg_vec = vec!["counter", "--format", "nginx", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,598 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use csv;
+use rustc_serialize::json::Json;
+use FileAggregation;
+use parquet_output;
+use record_handling::{AggregateELBRecord, AggregateMetrics, AggregationConfig, Dimension,
+                      DimensionValue};
+
+/// The percentiles of the backend processing time written when they are enabled.
+pub const PERCENTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+/// The formats the aggregates can be written in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// RFC 4180 CSV having a header row.
+    Csv,
+    /// A JSON array holding an object for each aggregate.
+    Json,
+    /// A JSON object for each aggregate on a line of its own.
+    Ndjson,
+    /// A Snappy compressed Parquet file having a typed column for each dimension and metric.
+    Parquet,
+}
+
+pub const OUTPUT_FORMAT_NAMES: [&str; 4] = ["csv", "json", "ndjson", "parquet"];
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Ndjson => write!(f, "ndjson"),
+            OutputFormat::Parquet => write!(f, "parquet"),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "parquet" => Ok(OutputFormat::Parquet),
+            _ => Err(format!("{} is not one of the output formats {:?}.", s, OUTPUT_FORMAT_NAMES)),
+        }
+    }
+}
+
+/// What the aggregates can be sorted by.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SortKey {
+    /// Sorts by the value of a dimension that is grouped by, in ascending order.
+    Dimension(Dimension),
+    /// Sorts by the number of requests, in descending order.
+    Requests,
+}
+
+impl Display for SortKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            SortKey::Dimension(dimension) => write!(f, "{}", dimension),
+            SortKey::Requests => write!(f, "requests"),
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SortKey, String> {
+        match s {
+            "requests" => Ok(SortKey::Requests),
+            _ => {
+                s.parse()
+                    .map(SortKey::Dimension)
+                    .map_err(|_| format!("{} is not requests or one of the dimensions.", s))
+            }
+        }
+    }
+}
+
+/// Controls how the aggregates are written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+    /// The file the aggregates are written to. They are written to stdout when it isn't set.
+    pub path: Option<PathBuf>,
+    /// The aggregates are sorted by these keys in turn, then by all of their dimensions in the
+    /// order they are grouped by, so the same aggregates are always written in the same order.
+    pub sort_by: Vec<SortKey>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> OutputConfig {
+        OutputConfig {
+            format: OutputFormat::Csv,
+            path: None,
+            sort_by: Vec::new(),
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Checks that the aggregates can be sorted by the sort keys, which have to be grouped by.
+    pub fn check_sort_keys(&self, config: &AggregationConfig) -> Result<(), String> {
+        for sort_key in &self.sort_by {
+            if let SortKey::Dimension(dimension) = *sort_key {
+                if !config.group_by.contains(&dimension) {
+                    return Err(format!("The aggregates can't be sorted by {} because they \
+                                        aren't grouped by it.",
+                                       dimension));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the aggregates of `final_agg` in the order they are written.
+pub fn sorted_aggregates<'a>(output_config: &OutputConfig,
+                             config: &AggregationConfig,
+                             final_agg: &'a FileAggregation)
+                             -> Vec<(&'a AggregateELBRecord, &'a AggregateMetrics)> {
+    let mut aggregates = final_agg.aggregation.iter().collect::<Vec<_>>();
+    aggregates.sort_by(|&(aggregate_a, total_a), &(aggregate_b, total_b)| {
+        output_config.sort_by
+            .iter()
+            .map(|sort_key| match *sort_key {
+                SortKey::Dimension(dimension) => {
+                    config.group_by
+                        .iter()
+                        .position(|grouped_by| *grouped_by == dimension)
+                        .map_or(Ordering::Equal, |index| {
+                            aggregate_a.dimensions[index].cmp(&aggregate_b.dimensions[index])
+                        })
+                }
+                SortKey::Requests => total_b.requests.cmp(&total_a.requests),
+            })
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| aggregate_a.cmp(aggregate_b))
+    });
+    aggregates
+}
+
+/// A value of an output column.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputValue {
+    Text(String),
+    Integer(i64),
+    Float(f64),
+    /// A value that isn't known, such as the backend of a request that never reached one.
+    Missing,
+}
+
+impl OutputValue {
+    fn csv_field(&self) -> String {
+        match *self {
+            OutputValue::Text(ref text) => text.clone(),
+            OutputValue::Integer(integer) => integer.to_string(),
+            OutputValue::Float(float) => format!("{:.6}", float),
+            OutputValue::Missing => String::new(),
+        }
+    }
+
+    fn json(&self) -> Json {
+        match *self {
+            OutputValue::Text(ref text) => Json::String(text.clone()),
+            OutputValue::Integer(integer) => Json::I64(integer),
+            // Rounded like the CSV output so that floating point sums, which depend on the order
+            // the workers' aggregates were merged in, don't change the output between runs.
+            OutputValue::Float(float) => Json::F64((float * 1e6).round() / 1e6),
+            OutputValue::Missing => Json::Null,
+        }
+    }
+}
+
+/// Returns the names of the output columns: the dimensions that are grouped by, in order,
+/// followed by the metrics.
+pub fn column_names(config: &AggregationConfig) -> Vec<String> {
+    let mut names = config.group_by
+        .iter()
+        .map(|dimension| dimension.to_string())
+        .collect::<Vec<_>>();
+    for metric in &["requests", "sent_bytes", "received_bytes", "backend_processing_time"] {
+        names.push((*metric).to_owned());
+    }
+    if config.backend_processing_time_percentiles {
+        for percentile in PERCENTILES.iter() {
+            names.push(format!("backend_processing_time_p{}", (percentile * 100.0).round()));
+        }
+    }
+    if config.distinct_clients {
+        names.push("distinct_clients".to_owned());
+    }
+    names
+}
+
+/// Returns the values of an aggregate in the order of `column_names`.
+pub fn row(config: &AggregationConfig,
+           dimensions: &[DimensionValue],
+           total: &AggregateMetrics)
+           -> Vec<OutputValue> {
+    let mut values = dimensions.iter().map(dimension_value).collect::<Vec<_>>();
+    values.push(OutputValue::Integer(total.requests));
+    values.push(OutputValue::Integer(total.sent_bytes as i64));
+    values.push(OutputValue::Integer(total.received_bytes as i64));
+    values.push(OutputValue::Float(total.backend_processing_time));
+    if config.backend_processing_time_percentiles {
+        for percentile in PERCENTILES.iter() {
+            let value = total.backend_processing_time_sketch
+                .as_ref()
+                .and_then(|sketch| sketch.quantile(*percentile));
+            values.push(value.map_or(OutputValue::Missing, OutputValue::Float));
+        }
+    }
+    if config.distinct_clients {
+        let estimate = total.distinct_clients.as_ref().map(|clients| clients.estimate() as i64);
+        values.push(estimate.map_or(OutputValue::Missing, OutputValue::Integer));
+    }
+    values
+}
+
+fn dimension_value(dimension: &DimensionValue) -> OutputValue {
+    match *dimension {
+        DimensionValue::Backend(None) |
+        DimensionValue::Resource(None) |
+        DimensionValue::Rendition(None) => OutputValue::Missing,
+        _ => OutputValue::Text(dimension.to_string()),
+    }
+}
+
+/// Writes the aggregates of `final_agg` to `writer` as the output config says.
+pub fn write_aggregation<W: Write + Send>(writer: W,
+                                          output_config: &OutputConfig,
+                                          config: &AggregationConfig,
+                                          final_agg: &FileAggregation)
+                                          -> io::Result<()> {
+    let names = column_names(config);
+    let rows = sorted_aggregates(output_config, config, final_agg)
+        .into_iter()
+        .map(|(aggregate, total)| row(config, &aggregate.dimensions, total));
+    match output_config.format {
+        OutputFormat::Csv => {
+            let mut csv_writer = csv::Writer::from_writer(writer);
+            csv_writer.write_record(&names)?;
+            for values in rows {
+                csv_writer.write_record(values.iter().map(OutputValue::csv_field))?;
+            }
+            csv_writer.flush()
+        }
+        OutputFormat::Json => {
+            let mut writer = writer;
+            write!(writer, "[")?;
+            for (i, values) in rows.enumerate() {
+                let separator = if i == 0 { "\n" } else { ",\n" };
+                write!(writer, "{}{}", separator, json_object(&names, &values))?;
+            }
+            writeln!(writer, "\n]")?;
+            writer.flush()
+        }
+        OutputFormat::Ndjson => {
+            let mut writer = writer;
+            for values in rows {
+                writeln!(writer, "{}", json_object(&names, &values))?;
+            }
+            writer.flush()
+        }
+        OutputFormat::Parquet => {
+            parquet_output::write_parquet(writer, output_config, config, final_agg)
+                .map_err(io::Error::from)
+        }
+    }
+}
+
+/// Writes the aggregates to the output config's file, or to stdout when it has none.
+pub fn write_output(output_config: &OutputConfig,
+                    config: &AggregationConfig,
+                    final_agg: &FileAggregation)
+                    -> io::Result<()> {
+    match output_config.path {
+        Some(ref path) => {
+            write_atomically(path, |writer| {
+                write_aggregation(writer, output_config, config, final_agg)
+            })
+        }
+        None => {
+            let mut stdout = BufWriter::new(io::stdout());
+            write_aggregation(&mut stdout, output_config, config, final_agg)?;
+            stdout.flush()
+        }
+    }
+}
+
+/// Writes a file through a temporary file in the same directory that is renamed to `path` once
+/// it is complete, so readers of `path` never see a partly written file. The temporary file is
+/// removed when the write fails.
+pub fn write_atomically<F>(path: &Path, write: F) -> io::Result<()>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()>
+{
+    let temp_path = temporary_path(path);
+    let result = File::create(&temp_path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()
+    });
+    match result.and_then(|_| fs::rename(&temp_path, path)) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(err)
+        }
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().and_then(|file_name| file_name.to_str()).unwrap_or("output");
+    path.with_file_name(format!(".{}.{}.tmp", file_name, process::id()))
+}
+
+// The members are written in the column order, which a BTreeMap backed Json::Object would lose.
+fn json_object(names: &[String], values: &[OutputValue]) -> String {
+    let members = names.iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}:{}", Json::String(name.clone()), value.json()))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", members.join(","))
+}
+
+#[cfg(test)]
+mod output_tests {
+
+    use std::{fs, io};
+    use std::io::Write;
+    use std::net::IpAddr;
+    use std::path::Path;
+    use chrono::TimeZone;
+    use chrono_tz::Tz;
+    use FileAggregation;
+    use record_handling::{AggregateELBRecord, AggregateMetrics, AggregationConfig, Dimension,
+                          DimensionValue, TimeBucket};
+    use test_common::run_int_test_in_test_dir;
+    use super::{OutputConfig, OutputFormat, OutputValue, SortKey};
+
+    fn test_config() -> AggregationConfig {
+        AggregationConfig {
+            group_by: vec![Dimension::System, Dimension::Time, Dimension::Backend],
+            ..AggregationConfig::default()
+        }
+    }
+
+    fn test_aggregation(system_name: &str) -> FileAggregation {
+        let mut final_agg = FileAggregation::default();
+        let day = Tz::UTC.with_ymd_and_hms(2015, 8, 15, 0, 0, 0).unwrap();
+        let key = AggregateELBRecord {
+            dimensions: vec![DimensionValue::System(system_name.to_owned()),
+                             DimensionValue::Time(day, TimeBucket::Day),
+                             DimensionValue::Backend(None)],
+        };
+        let total = AggregateMetrics {
+            requests: 2,
+            sent_bytes: 100,
+            received_bytes: 10,
+            backend_processing_time: 0.5,
+            ..AggregateMetrics::default()
+        };
+        final_agg.aggregation.insert(key, total);
+        final_agg
+    }
+
+    fn written(format: OutputFormat, config: &AggregationConfig, final_agg: &FileAggregation)
+               -> String {
+        let mut buffer = Vec::new();
+        let output_config = OutputConfig { format, ..OutputConfig::default() };
+        super::write_aggregation(&mut buffer, &output_config, config, final_agg).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn output_formats_should_round_trip_through_their_names() {
+        for name in super::OUTPUT_FORMAT_NAMES.iter() {
+            assert_eq!(name.parse::<OutputFormat>().unwrap().to_string(), *name)
+        }
+        assert!("xml".parse::<OutputFormat>().is_err())
+    }
+
+    #[test]
+    fn column_names_should_include_the_enabled_metrics() {
+        let config = AggregationConfig {
+            group_by: vec![Dimension::Client],
+            backend_processing_time_percentiles: true,
+            distinct_clients: true,
+            ..AggregationConfig::default()
+        };
+
+        assert_eq!(super::column_names(&config),
+                   vec!["client",
+                        "requests",
+                        "sent_bytes",
+                        "received_bytes",
+                        "backend_processing_time",
+                        "backend_processing_time_p50",
+                        "backend_processing_time_p95",
+                        "backend_processing_time_p99",
+                        "distinct_clients"])
+    }
+
+    #[test]
+    fn row_should_leave_out_the_metrics_that_are_not_kept() {
+        let config = AggregationConfig {
+            group_by: vec![Dimension::Client],
+            distinct_clients: true,
+            ..AggregationConfig::default()
+        };
+        let client = DimensionValue::Client("172.16.1.6".parse::<IpAddr>().unwrap());
+
+        assert_eq!(super::row(&config, &[client], &AggregateMetrics::default()),
+                   vec![OutputValue::Text("172.16.1.6".to_owned()),
+                        OutputValue::Integer(0),
+                        OutputValue::Integer(0),
+                        OutputValue::Integer(0),
+                        OutputValue::Float(0.0),
+                        OutputValue::Missing])
+    }
+
+    #[test]
+    fn csv_output_should_have_a_header_and_quote_the_fields_that_need_it() {
+        let final_agg = test_aggregation("wjla, \"streaming\"");
+
+        assert_eq!(written(OutputFormat::Csv, &test_config(), &final_agg),
+                   "system,time,backend,requests,sent_bytes,received_bytes,\
+                    backend_processing_time\n\
+                    \"wjla, \"\"streaming\"\"\",2015-08-15,,2,100,10,0.500000\n")
+    }
+
+    #[test]
+    fn json_output_should_hold_an_object_for_each_aggregate_in_column_order() {
+        let final_agg = test_aggregation("wjla \"streaming\"");
+
+        assert_eq!(written(OutputFormat::Json, &test_config(), &final_agg),
+                   "[\n{\"system\":\"wjla \\\"streaming\\\"\",\"time\":\"2015-08-15\",\
+                    \"backend\":null,\"requests\":2,\"sent_bytes\":100,\"received_bytes\":10,\
+                    \"backend_processing_time\":0.5}\n]\n")
+    }
+
+    #[test]
+    fn json_output_should_be_an_empty_array_when_there_are_no_aggregates() {
+        assert_eq!(written(OutputFormat::Json, &test_config(), &FileAggregation::default()),
+                   "[\n]\n")
+    }
+
+    #[test]
+    fn ndjson_output_should_write_an_object_to_each_line() {
+        let final_agg = test_aggregation("wpix");
+
+        assert_eq!(written(OutputFormat::Ndjson, &test_config(), &final_agg),
+                   "{\"system\":\"wpix\",\"time\":\"2015-08-15\",\"backend\":null,\
+                    \"requests\":2,\"sent_bytes\":100,\"received_bytes\":10,\
+                    \"backend_processing_time\":0.5}\n")
+    }
+
+    fn client_aggregation() -> FileAggregation {
+        let mut final_agg = FileAggregation::default();
+        for &(system_name, client, requests) in &[("wpix", "10.0.0.2", 1),
+                                                  ("kcscout", "10.0.0.1", 1),
+                                                  ("wpix", "10.0.0.1", 5),
+                                                  ("kcscout", "10.0.0.3", 3)] {
+            let key = AggregateELBRecord {
+                dimensions: vec![DimensionValue::System(system_name.to_owned()),
+                                 DimensionValue::Client(client.parse().unwrap())],
+            };
+            let total = AggregateMetrics { requests, ..AggregateMetrics::default() };
+            final_agg.aggregation.insert(key, total);
+        }
+        final_agg
+    }
+
+    fn sorted_rows(sort_by: Vec<SortKey>) -> Vec<String> {
+        let config = AggregationConfig {
+            group_by: vec![Dimension::System, Dimension::Client],
+            ..AggregationConfig::default()
+        };
+        let output_config = OutputConfig { sort_by, ..OutputConfig::default() };
+        let final_agg = client_aggregation();
+        super::sorted_aggregates(&output_config, &config, &final_agg)
+            .iter()
+            .map(|&(aggregate, _)| {
+                let dimensions = aggregate.dimensions
+                    .iter()
+                    .map(|dimension| dimension.to_string())
+                    .collect::<Vec<_>>();
+                dimensions.join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sort_keys_should_round_trip_through_their_names() {
+        for name in &["system", "time", "client", "requests"] {
+            assert_eq!(name.parse::<SortKey>().unwrap().to_string(), *name)
+        }
+        assert!("day".parse::<SortKey>().is_err())
+    }
+
+    #[test]
+    fn sorted_aggregates_should_sort_by_the_dimensions_by_default() {
+        assert_eq!(sorted_rows(Vec::new()),
+                   vec!["kcscout 10.0.0.1", "kcscout 10.0.0.3", "wpix 10.0.0.1", "wpix 10.0.0.2"])
+    }
+
+    #[test]
+    fn sorted_aggregates_should_sort_by_the_sort_keys_in_turn() {
+        assert_eq!(sorted_rows(vec![SortKey::Dimension(Dimension::Client)]),
+                   vec!["kcscout 10.0.0.1", "wpix 10.0.0.1", "wpix 10.0.0.2", "kcscout 10.0.0.3"]);
+        assert_eq!(sorted_rows(vec![SortKey::Requests]),
+                   vec!["wpix 10.0.0.1", "kcscout 10.0.0.3", "kcscout 10.0.0.1", "wpix 10.0.0.2"])
+    }
+
+    #[test]
+    fn check_sort_keys_should_reject_a_dimension_that_is_not_grouped_by() {
+        let config = AggregationConfig {
+            group_by: vec![Dimension::System],
+            ..AggregationConfig::default()
+        };
+        let by_system = OutputConfig {
+            sort_by: vec![SortKey::Dimension(Dimension::System), SortKey::Requests],
+            ..OutputConfig::default()
+        };
+        let by_client = OutputConfig {
+            sort_by: vec![SortKey::Dimension(Dimension::Client)],
+            ..OutputConfig::default()
+        };
+
+        assert!(by_system.check_sort_keys(&config).is_ok());
+        assert!(by_client.check_sort_keys(&config).is_err())
+    }
+
+    #[test]
+    fn write_atomically_should_replace_the_file_once_it_is_complete() {
+        run_int_test_in_test_dir(|dir| {
+            let dir = Path::new(dir);
+            let path = dir.join("report.csv");
+            fs::write(&path, "old report").unwrap();
+
+            super::write_atomically(&path, |writer| writer.write_all(b"new report")).unwrap();
+
+            assert_eq!(fs::read_to_string(&path).unwrap(), "new report");
+            assert_eq!(fs::read_dir(dir).unwrap().count(), 1)
+        })
+    }
+
+    #[test]
+    fn write_atomically_should_leave_the_file_alone_when_the_write_fails() {
+        run_int_test_in_test_dir(|dir| {
+            let dir = Path::new(dir);
+            let path = dir.join("report.csv");
+            fs::write(&path, "old report").unwrap();
+
+            let result = super::write_atomically(&path, |writer| {
+                writer.write_all(b"half a rep")?;
+                Err(io::Error::other("disk full"))
+            });
+
+            assert!(result.is_err());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "old report");
+            assert_eq!(fs::read_dir(dir).unwrap().count(), 1)
+        })
+    }
+
+    #[test]
+    fn write_output_should_write_the_aggregates_to_the_output_path() {
+        run_int_test_in_test_dir(|dir| {
+            let dir = Path::new(dir);
+            let output_config = OutputConfig {
+                format: OutputFormat::Ndjson,
+                path: Some(dir.join("report.ndjson")),
+                ..OutputConfig::default()
+            };
+            let final_agg = test_aggregation("wpix");
+
+            super::write_output(&output_config, &test_config(), &final_agg).unwrap();
+
+            assert_eq!(fs::read_to_string(dir.join("report.ndjson")).unwrap(),
+                       written(OutputFormat::Ndjson, &test_config(), &final_agg))
+        })
+    }
+}
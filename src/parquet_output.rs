@@ -0,0 +1,223 @@
+use std::io::Write;
+use std::sync::Arc;
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_array::builder::{Float64Builder, Int64Builder, StringBuilder, TimestampSecondBuilder};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::errors::Result;
+use parquet::file::properties::WriterProperties;
+use FileAggregation;
+use output;
+use output::{OutputConfig, OutputValue};
+use record_handling::{AggregationConfig, Dimension, DimensionValue};
+
+/// The number of aggregates in each record batch, which bounds the memory the columns take.
+const BATCH_SIZE: usize = 64 * 1024;
+
+/// Returns the Arrow schema of the aggregates: a column for each dimension that is grouped by,
+/// followed by the metrics, with the names of the CSV columns.
+pub fn schema(config: &AggregationConfig) -> Schema {
+    let names = output::column_names(config);
+    let mut fields = config.group_by
+        .iter()
+        .zip(&names)
+        .map(|(dimension, name)| {
+            match *dimension {
+                Dimension::Time => {
+                    let time_zone = config.time_zone.name().into();
+                    Field::new(name.as_str(),
+                               DataType::Timestamp(TimeUnit::Second, Some(time_zone)),
+                               false)
+                }
+                Dimension::System | Dimension::Client => {
+                    Field::new(name.as_str(), DataType::Utf8, false)
+                }
+                Dimension::Backend | Dimension::Resource | Dimension::Rendition => {
+                    Field::new(name.as_str(), DataType::Utf8, true)
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    for name in &names[config.group_by.len()..] {
+        let field = match name.as_str() {
+            "requests" | "sent_bytes" | "received_bytes" => {
+                Field::new(name.as_str(), DataType::Int64, false)
+            }
+            "backend_processing_time" => Field::new(name.as_str(), DataType::Float64, false),
+            "distinct_clients" => Field::new(name.as_str(), DataType::Int64, true),
+            _ => Field::new(name.as_str(), DataType::Float64, true),
+        };
+        fields.push(field);
+    }
+    Schema::new(fields)
+}
+
+enum ColumnBuilder {
+    Text(StringBuilder),
+    Timestamp(TimestampSecondBuilder),
+    Integer(Int64Builder),
+    Float(Float64Builder),
+}
+
+impl ColumnBuilder {
+    fn new(field: &Field) -> ColumnBuilder {
+        match *field.data_type() {
+            DataType::Timestamp(_, ref time_zone) => {
+                ColumnBuilder::Timestamp(TimestampSecondBuilder::new()
+                    .with_timezone_opt(time_zone.clone()))
+            }
+            DataType::Int64 => ColumnBuilder::Integer(Int64Builder::new()),
+            DataType::Float64 => ColumnBuilder::Float(Float64Builder::new()),
+            _ => ColumnBuilder::Text(StringBuilder::new()),
+        }
+    }
+
+    fn append_dimension(&mut self, dimension: &DimensionValue) {
+        match (self, dimension) {
+            (ColumnBuilder::Timestamp(builder), DimensionValue::Time(start, _)) => {
+                builder.append_value(start.timestamp())
+            }
+            (ColumnBuilder::Text(builder), DimensionValue::Backend(None)) |
+            (ColumnBuilder::Text(builder), DimensionValue::Resource(None)) |
+            (ColumnBuilder::Text(builder), DimensionValue::Rendition(None)) => {
+                builder.append_null()
+            }
+            (ColumnBuilder::Text(builder), _) => builder.append_value(dimension.to_string()),
+            _ => unreachable!("The column of a dimension doesn't match its value."),
+        }
+    }
+
+    fn append_metric(&mut self, value: &OutputValue) {
+        match (self, value) {
+            (ColumnBuilder::Integer(builder), OutputValue::Integer(integer)) => {
+                builder.append_value(*integer)
+            }
+            (ColumnBuilder::Float(builder), OutputValue::Float(float)) => {
+                builder.append_value(*float)
+            }
+            (ColumnBuilder::Integer(builder), OutputValue::Missing) => builder.append_null(),
+            (ColumnBuilder::Float(builder), OutputValue::Missing) => builder.append_null(),
+            _ => unreachable!("The column of a metric doesn't match its value."),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match *self {
+            ColumnBuilder::Text(ref mut builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Timestamp(ref mut builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Integer(ref mut builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Float(ref mut builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+/// Writes the aggregates of `final_agg` to `writer` as a Snappy compressed Parquet file, in the
+/// order they are written in the other formats.
+pub fn write_parquet<W: Write + Send>(writer: W,
+                                      output_config: &OutputConfig,
+                                      config: &AggregationConfig,
+                                      final_agg: &FileAggregation)
+                                      -> Result<()> {
+    let schema = Arc::new(schema(config));
+    let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+    let mut parquet_writer = ArrowWriter::try_new(writer, schema.clone(), Some(properties))?;
+    let mut columns = schema.fields()
+        .iter()
+        .map(|field| ColumnBuilder::new(field))
+        .collect::<Vec<_>>();
+
+    let aggregates = output::sorted_aggregates(output_config, config, final_agg);
+    for batch in aggregates.chunks(BATCH_SIZE) {
+        for &(aggregate, total) in batch {
+            let (dimension_columns, metric_columns) = columns.split_at_mut(config.group_by.len());
+            for (column, dimension) in dimension_columns.iter_mut().zip(&aggregate.dimensions) {
+                column.append_dimension(dimension);
+            }
+            // Without any dimensions a row holds only the metrics.
+            let metrics = output::row(config, &[], total);
+            for (column, value) in metric_columns.iter_mut().zip(&metrics) {
+                column.append_metric(value);
+            }
+        }
+        let arrays = columns.iter_mut().map(ColumnBuilder::finish).collect();
+        parquet_writer.write(&RecordBatch::try_new(schema.clone(), arrays)?)?;
+    }
+    parquet_writer.close()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod parquet_output_tests {
+
+    use arrow_array::{Array, Int64Array, StringArray, TimestampSecondArray};
+    use arrow_schema::{DataType, TimeUnit};
+    use bytes::Bytes;
+    use chrono::{TimeZone, Utc};
+    use chrono_tz::Tz;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use output::OutputConfig;
+    use record_handling::{AggregationConfig, Dimension};
+    use test_common::test_aggregation;
+
+    fn test_config() -> AggregationConfig {
+        AggregationConfig {
+            group_by: vec![Dimension::System, Dimension::Time, Dimension::Backend],
+            time_zone: Tz::America__New_York,
+            distinct_clients: true,
+            ..AggregationConfig::default()
+        }
+    }
+
+    #[test]
+    fn schema_should_type_the_time_as_a_timestamp_in_the_time_zone() {
+        let schema = super::schema(&test_config());
+
+        let names = schema.fields().iter().map(|field| field.name().as_str()).collect::<Vec<_>>();
+        assert_eq!(names,
+                   vec!["system",
+                        "time",
+                        "backend",
+                        "requests",
+                        "sent_bytes",
+                        "received_bytes",
+                        "backend_processing_time",
+                        "distinct_clients"]);
+        assert_eq!(*schema.field(1).data_type(),
+                   DataType::Timestamp(TimeUnit::Second, Some("America/New_York".into())));
+        assert!(schema.field(2).is_nullable());
+        assert!(!schema.field(3).is_nullable());
+    }
+
+    #[test]
+    fn write_parquet_should_write_the_sorted_aggregates() {
+        let mut written = Vec::new();
+
+        super::write_parquet(&mut written,
+                             &OutputConfig::default(),
+                             &test_config(),
+                             &test_aggregation(&Tz::America__New_York))
+            .unwrap();
+
+        let batches = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(written))
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        let column = |name| batch.column_by_name(name).unwrap();
+        let systems = column("system").as_any().downcast_ref::<StringArray>().unwrap();
+        let times = column("time").as_any().downcast_ref::<TimestampSecondArray>().unwrap();
+        let backends = column("backend").as_any().downcast_ref::<StringArray>().unwrap();
+        let requests = column("requests").as_any().downcast_ref::<Int64Array>().unwrap();
+        let distinct_clients = column("distinct_clients");
+        assert_eq!(systems.iter().collect::<Vec<_>>(), vec![Some("kcscout"), Some("wpix")]);
+        assert_eq!(times.value(0), Utc.with_ymd_and_hms(2015, 8, 15, 4, 0, 0).unwrap().timestamp());
+        assert_eq!(backends.iter().collect::<Vec<_>>(), vec![Some("172.16.1.5:9000"), None]);
+        assert_eq!(requests.values().to_vec(), vec![8, 2]);
+        assert_eq!(distinct_clients.null_count(), 2);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+/// The relative error of the quantiles a sketch returns.
+pub const RELATIVE_ACCURACY: f64 = 0.01;
+
+// Values below this are counted as zero. It is well below the microsecond resolution of the
+// processing times in the logs.
+const MIN_INDEXABLE_VALUE: f64 = 1e-9;
+
+/// A DDSketch style quantile sketch of non-negative values.
+///
+/// Values are counted in buckets whose bounds grow geometrically, so any quantile is returned
+/// within `RELATIVE_ACCURACY` of the true value. Sketches built on different threads merge
+/// without losing accuracy by adding up their buckets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantileSketch {
+    gamma: f64,
+    zero_count: u64,
+    bucket_counts: BTreeMap<i32, u64>,
+    count: u64,
+}
+
+impl Default for QuantileSketch {
+    fn default() -> QuantileSketch {
+        QuantileSketch {
+            gamma: (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY),
+            zero_count: 0,
+            bucket_counts: BTreeMap::new(),
+            count: 0,
+        }
+    }
+}
+
+impl QuantileSketch {
+    /// Counts a value. Negative values are ignored.
+    pub fn add(&mut self, value: f64) {
+        if value < 0.0 {
+            return;
+        }
+        if value < MIN_INDEXABLE_VALUE {
+            self.zero_count += 1;
+        } else {
+            *self.bucket_counts.entry(self.bucket_index(value)).or_insert(0) += 1;
+        }
+        self.count += 1;
+    }
+
+    pub fn merge(&mut self, other: &QuantileSketch) {
+        self.zero_count += other.zero_count;
+        for (index, count) in &other.bucket_counts {
+            *self.bucket_counts.entry(*index).or_insert(0) += *count;
+        }
+        self.count += other.count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the value at `quantile`, which is clamped to [0, 1], or `None` if the sketch
+    /// is empty.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (quantile.clamp(0.0, 1.0) * (self.count - 1) as f64).floor() as u64;
+        if rank < self.zero_count {
+            return Some(0.0);
+        }
+
+        let mut num_below = self.zero_count;
+        for (index, count) in &self.bucket_counts {
+            num_below += *count;
+            if rank < num_below {
+                return Some(self.bucket_value(*index));
+            }
+        }
+        self.bucket_counts.keys().next_back().map(|index| self.bucket_value(*index))
+    }
+
+    // Bucket i holds the values in (gamma^(i-1), gamma^i].
+    fn bucket_index(&self, value: f64) -> i32 {
+        (value.ln() / self.gamma.ln()).ceil() as i32
+    }
+
+    // The point of a bucket that is within the relative accuracy of both of its bounds.
+    fn bucket_value(&self, index: i32) -> f64 {
+        2.0 * self.gamma.powi(index) / (self.gamma + 1.0)
+    }
+}
+
+#[cfg(test)]
+mod quantile_sketch_tests {
+
+    use super::{QuantileSketch, RELATIVE_ACCURACY};
+
+    fn assert_within_relative_accuracy(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() <= expected * RELATIVE_ACCURACY,
+                "{} is not within {} of {}",
+                actual,
+                RELATIVE_ACCURACY,
+                expected);
+    }
+
+    #[test]
+    fn quantile_should_return_none_when_the_sketch_is_empty() {
+        let sketch = QuantileSketch::default();
+
+        assert_eq!(sketch.quantile(0.5), None)
+    }
+
+    #[test]
+    fn quantile_should_be_within_the_relative_accuracy_of_the_exact_quantile() {
+        let mut sketch = QuantileSketch::default();
+        for millis in 1..1001 {
+            sketch.add(f64::from(millis) / 1000.0);
+        }
+
+        assert_within_relative_accuracy(sketch.quantile(0.5).unwrap(), 0.5);
+        assert_within_relative_accuracy(sketch.quantile(0.95).unwrap(), 0.95);
+        assert_within_relative_accuracy(sketch.quantile(0.99).unwrap(), 0.99);
+        assert_within_relative_accuracy(sketch.quantile(1.0).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn add_should_count_zeros_and_ignore_negative_values() {
+        let mut sketch = QuantileSketch::default();
+        sketch.add(0.0);
+        sketch.add(0.0);
+        sketch.add(-1.0);
+        sketch.add(2.0);
+
+        assert_eq!(sketch.count(), 3);
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        assert_within_relative_accuracy(sketch.quantile(1.0).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn merging_sketches_should_equal_a_sketch_of_all_of_the_values() {
+        let mut all = QuantileSketch::default();
+        let mut evens = QuantileSketch::default();
+        let mut odds = QuantileSketch::default();
+        for millis in 0..500 {
+            let value = f64::from(millis) / 1000.0;
+            all.add(value);
+            if millis % 2 == 0 {
+                evens.add(value);
+            } else {
+                odds.add(value);
+            }
+        }
+
+        evens.merge(&odds);
+
+        assert_eq!(evens, all)
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use chrono::{DateTime, Duration, LocalResult, NaiveDate, Offset, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use record_handling::LogRecord;
+use system_names;
+
+/// A predicate a record must satisfy to be counted.
+///
+/// The records a filter rejects are counted under its description, which is how it was given
+/// on the command line.
+#[derive(Clone, Debug)]
+pub enum RecordFilter {
+    /// Keeps the records whose status code matches any of the patterns, such as `200` or `2xx`.
+    Status(Vec<StatusPattern>),
+    /// Keeps the records whose request method is any of the methods, ignoring case.
+    Method(Vec<String>),
+    /// Drops the records whose request path matches.
+    ExcludePath(Regex),
+    /// Keeps the records whose timestamp is in the range.
+    Time(TimeRange),
+}
+
+impl RecordFilter {
+    pub fn accepts(&self, record: &LogRecord) -> bool {
+        match *self {
+            RecordFilter::Status(ref patterns) => {
+                patterns.iter().any(|pattern| pattern.matches(record.status_code))
+            }
+            RecordFilter::Method(ref methods) => {
+                methods.iter().any(|method| method.eq_ignore_ascii_case(record.request_method))
+            }
+            RecordFilter::ExcludePath(ref regex) => {
+                !regex.is_match(system_names::request_path(record.request_url))
+            }
+            RecordFilter::Time(ref time_range) => time_range.contains(&record.timestamp),
+        }
+    }
+}
+
+impl Display for RecordFilter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            RecordFilter::Status(ref patterns) => {
+                let patterns = patterns.iter()
+                    .map(|pattern| pattern.0.as_str())
+                    .collect::<Vec<_>>();
+                write!(f, "status {}", patterns.join(","))
+            }
+            RecordFilter::Method(ref methods) => write!(f, "method {}", methods.join(",")),
+            RecordFilter::ExcludePath(ref regex) => write!(f, "exclude-path-regex {}", regex),
+            RecordFilter::Time(ref time_range) => write!(f, "{}", time_range),
+        }
+    }
+}
+
+/// A half open range of time, [since, until), where a missing bound is unbounded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimeRange {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl TimeRange {
+    pub fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    pub fn contains(&self, timestamp: &DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| *timestamp >= since) &&
+        self.until.is_none_or(|until| *timestamp < until)
+    }
+
+    /// Returns whether any of the times in [start, end) are in the range.
+    pub fn overlaps(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| *end > since) &&
+        self.until.is_none_or(|until| *start < until)
+    }
+}
+
+impl Display for TimeRange {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let bounds = [("since", self.since), ("until", self.until)]
+            .iter()
+            .filter_map(|&(name, bound)| {
+                bound.map(|bound| {
+                    format!("{} {}", name, bound.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+                })
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", bounds.join(" "))
+    }
+}
+
+/// Parses an RFC 3339 timestamp, or a date, which is taken as its midnight in `time_zone`.
+pub fn parse_time_bound(s: &str, time_zone: &Tz) -> Result<DateTime<Utc>, String> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(s) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    let midnight = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .ok_or_else(|| format!("{} is not an RFC 3339 timestamp or a YYYY-MM-DD date.", s))?;
+    let midnight = match time_zone.from_local_datetime(&midnight) {
+        LocalResult::Single(midnight) | LocalResult::Ambiguous(midnight, _) => midnight,
+        // The clocks went forward over midnight, so the day starts when they did.
+        LocalResult::None => {
+            let offset_before = time_zone
+                .offset_from_utc_datetime(&(midnight - Duration::days(1)))
+                .fix();
+            time_zone.from_utc_datetime(&(midnight - offset_before))
+        }
+    };
+    Ok(midnight.with_timezone(&Utc))
+}
+
+/// A three character status code in which an `x` matches any digit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusPattern(String);
+
+impl StatusPattern {
+    pub fn matches(&self, status_code: u16) -> bool {
+        let digits = [status_code / 100, status_code / 10 % 10, status_code % 10];
+        (100..1000).contains(&status_code) &&
+        self.0.bytes().zip(&digits).all(|(pattern, &digit)| {
+            pattern == b'x' || u16::from(pattern - b'0') == digit
+        })
+    }
+}
+
+impl FromStr for StatusPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StatusPattern, String> {
+        let pattern = s.to_lowercase();
+        if pattern.len() == 3 && pattern.chars().all(|c| c == 'x' || c.is_ascii_digit()) {
+            Ok(StatusPattern(pattern))
+        } else {
+            Err(format!("{} is not a status code or a status class such as 2xx.", s))
+        }
+    }
+}
+
+/// Returns the index of the first filter that rejects the record, if any.
+pub fn rejecting_filter(filters: &[RecordFilter], record: &LogRecord) -> Option<usize> {
+    filters.iter().position(|filter| !filter.accepts(record))
+}
+
+/// Adds the rejected records counted in `src_rejected_records` to `dst_rejected_records`. Either
+/// may be shorter than the list of filters when its last filters rejected nothing.
+pub fn merge_rejected_records(src_rejected_records: &[u64], dst_rejected_records: &mut Vec<u64>) {
+    if dst_rejected_records.len() < src_rejected_records.len() {
+        dst_rejected_records.resize(src_rejected_records.len(), 0);
+    }
+    for (dst, src) in dst_rejected_records.iter_mut().zip(src_rejected_records) {
+        *dst += *src;
+    }
+}
+
+#[cfg(test)]
+mod record_filter_tests {
+
+    use chrono::{TimeZone, Utc};
+    use chrono_tz::Tz;
+    use regex::Regex;
+    use record_handling::LogRecord;
+    use super::{RecordFilter, StatusPattern, TimeRange};
+
+    fn test_log_record(status_code: u16,
+                       request_method: &'static str,
+                       request_url: &'static str)
+                       -> LogRecord<'static> {
+        LogRecord {
+            timestamp: Utc.with_ymd_and_hms(2015, 8, 15, 23, 43, 5).unwrap(),
+            client_address: "172.16.1.6".parse().unwrap(),
+            backend_address: None,
+            status_code,
+            request_method,
+            request_url,
+            sent_bytes: 7582,
+            received_bytes: 0,
+            backend_processing_time: 0.000039,
+        }
+    }
+
+    #[test]
+    fn status_patterns_should_match_exact_codes_and_classes() {
+        let class = "2XX".parse::<StatusPattern>().unwrap();
+        let exact = "304".parse::<StatusPattern>().unwrap();
+
+        assert!(class.matches(200));
+        assert!(class.matches(206));
+        assert!(!class.matches(304));
+        assert!(exact.matches(304));
+        assert!(!exact.matches(301));
+        assert!(!class.matches(20));
+        assert!(!exact.matches(3040));
+        assert!("2x".parse::<StatusPattern>().is_err());
+        assert!("2yy".parse::<StatusPattern>().is_err());
+    }
+
+    #[test]
+    fn filters_should_accept_only_the_matching_records() {
+        let url = "http://ie.trafficland.com:80/404299/full?system=wpix";
+        let health_check = "http://10.0.0.1:80/health?system=wpix";
+        let status = RecordFilter::Status(vec!["2xx".parse::<StatusPattern>().unwrap()]);
+        let method = RecordFilter::Method(vec!["get".to_owned()]);
+        let exclude_path = RecordFilter::ExcludePath(Regex::new("^/health").unwrap());
+
+        assert!(status.accepts(&test_log_record(200, "GET", url)));
+        assert!(!status.accepts(&test_log_record(404, "GET", url)));
+        assert!(method.accepts(&test_log_record(200, "GET", url)));
+        assert!(!method.accepts(&test_log_record(200, "HEAD", url)));
+        assert!(exclude_path.accepts(&test_log_record(200, "GET", url)));
+        assert!(!exclude_path.accepts(&test_log_record(200, "GET", health_check)));
+    }
+
+    #[test]
+    fn rejecting_filter_should_return_the_first_filter_that_rejects_the_record() {
+        let filters = [RecordFilter::Status(vec!["2xx".parse::<StatusPattern>().unwrap()]),
+                       RecordFilter::Method(vec!["GET".to_owned()])];
+        let url = "http://ie.trafficland.com:80/404299/full";
+
+        assert_eq!(super::rejecting_filter(&filters, &test_log_record(200, "GET", url)), None);
+        assert_eq!(super::rejecting_filter(&filters, &test_log_record(500, "HEAD", url)), Some(0));
+        assert_eq!(super::rejecting_filter(&filters, &test_log_record(200, "HEAD", url)), Some(1));
+    }
+
+    #[test]
+    fn merge_rejected_records_should_add_the_counts_of_each_filter() {
+        let mut dst_rejected_records = vec![1];
+
+        super::merge_rejected_records(&[2, 3], &mut dst_rejected_records);
+        super::merge_rejected_records(&[], &mut dst_rejected_records);
+
+        assert_eq!(dst_rejected_records, vec![3, 3]);
+    }
+
+    #[test]
+    fn time_filters_should_accept_the_records_in_the_half_open_range() {
+        let url = "http://ie.trafficland.com:80/404299/full";
+        let time = RecordFilter::Time(TimeRange {
+            since: Some(Utc.with_ymd_and_hms(2015, 8, 15, 23, 43, 5).unwrap()),
+            until: Some(Utc.with_ymd_and_hms(2015, 8, 16, 0, 0, 0).unwrap()),
+        });
+        let later_record = LogRecord {
+            timestamp: Utc.with_ymd_and_hms(2015, 8, 16, 0, 0, 0).unwrap(),
+            ..test_log_record(200, "GET", url)
+        };
+
+        assert!(time.accepts(&test_log_record(200, "GET", url)));
+        assert!(!time.accepts(&later_record));
+        assert_eq!(time.to_string(), "since 2015-08-15T23:43:05Z until 2015-08-16T00:00:00Z");
+    }
+
+    #[test]
+    fn time_ranges_should_overlap_the_periods_that_share_a_time_with_them() {
+        let august = TimeRange {
+            since: Some(Utc.with_ymd_and_hms(2015, 8, 1, 0, 0, 0).unwrap()),
+            until: Some(Utc.with_ymd_and_hms(2015, 9, 1, 0, 0, 0).unwrap()),
+        };
+        let day = |month, day| Utc.with_ymd_and_hms(2015, month, day, 0, 0, 0).unwrap();
+
+        assert!(august.overlaps(&day(7, 31), &day(8, 2)));
+        assert!(august.overlaps(&day(8, 31), &day(9, 1)));
+        assert!(!august.overlaps(&day(7, 30), &day(8, 1)));
+        assert!(!august.overlaps(&day(9, 1), &day(9, 2)));
+        assert!(TimeRange::default().overlaps(&day(1, 1), &day(1, 2)));
+    }
+
+    #[test]
+    fn parse_time_bound_should_take_dates_as_midnight_in_the_time_zone() {
+        assert_eq!(super::parse_time_bound("2015-08-01", &Tz::UTC),
+                   Ok(Utc.with_ymd_and_hms(2015, 8, 1, 0, 0, 0).unwrap()));
+        assert_eq!(super::parse_time_bound("2015-08-01", &Tz::America__New_York),
+                   Ok(Utc.with_ymd_and_hms(2015, 8, 1, 4, 0, 0).unwrap()));
+        assert_eq!(super::parse_time_bound("2015-08-01T12:00:00+02:00", &Tz::UTC),
+                   Ok(Utc.with_ymd_and_hms(2015, 8, 1, 10, 0, 0).unwrap()));
+        assert!(super::parse_time_bound("August", &Tz::UTC).is_err());
+    }
+
+    #[test]
+    fn parse_time_bound_should_start_a_date_without_a_midnight_when_the_clocks_went_forward() {
+        assert_eq!(super::parse_time_bound("2018-11-04", &Tz::America__Sao_Paulo),
+                   Ok(Utc.with_ymd_and_hms(2018, 11, 4, 3, 0, 0).unwrap()));
+    }
+}
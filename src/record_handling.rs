@@ -1,66 +1,652 @@
 use std::io::Write;
 
-use chrono::{Date, DateTime, UTC};
-use std::net::Ipv4Addr;
-use regex::Regex;
-use ELBRecordAggregation;
+use chrono::{Datelike, DateTime, Duration, LocalResult, Offset, TimeZone,
+             Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use std::borrow::Cow;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use {ELBRecordAggregation, FileAggregation};
 use elp;
+use alb;
+use quantile_sketch::QuantileSketch;
+use hyperloglog::HyperLogLog;
+use record_filters;
+use record_filters::RecordFilter;
+use system_names;
+use system_names::{ExtractionRule, SystemAliases, SystemNameNormalization, TokenRegistry};
+use std::collections::HashMap;
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+/// The key of an aggregate. It holds one value for each of the dimensions the records are
+/// grouped by, in the order the dimensions were requested.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct AggregateELBRecord {
-    pub day: Date<UTC>,
-    pub client_address: Ipv4Addr,
-    pub system_name: String,
+    pub dimensions: Vec<DimensionValue>,
 }
 
 impl AggregateELBRecord {
-    fn new(day: DateTime<UTC>, client_address: Ipv4Addr, system: String) -> AggregateELBRecord {
+    fn new(config: &AggregationConfig,
+           record: &LogRecord,
+           system_name: &str)
+           -> AggregateELBRecord {
         AggregateELBRecord {
-            day: day.date(),
-            client_address: client_address,
-            system_name: system,
+            dimensions: config.group_by
+                .iter()
+                .map(|dimension| dimension.value(config, record, system_name))
+                .collect(),
         }
     }
 }
 
-pub fn try_parse_record(possible_record: &str, dst_agg: &mut ELBRecordAggregation) -> () {
-    match elp::parse_record(possible_record) {
+/// The fields of a parsed record, whatever its log format, that records are aggregated by.
+pub struct LogRecord<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub client_address: IpAddr,
+    pub backend_address: Option<SocketAddr>,
+    /// The status code the load balancer returned.
+    pub status_code: u16,
+    pub request_method: &'a str,
+    pub request_url: &'a str,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    /// Negative when the load balancer could not send the request to a backend.
+    pub backend_processing_time: f32,
+}
+
+/// The totals kept for each aggregate.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AggregateMetrics {
+    pub requests: i64,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    /// In seconds. Requests that never reached a backend add nothing.
+    pub backend_processing_time: f64,
+    /// Only kept when the percentiles are enabled in the AggregationConfig.
+    pub backend_processing_time_sketch: Option<QuantileSketch>,
+    /// Only kept when the distinct clients are enabled in the AggregationConfig.
+    pub distinct_clients: Option<HyperLogLog>,
+}
+
+impl AggregateMetrics {
+    fn new(config: &AggregationConfig) -> AggregateMetrics {
+        AggregateMetrics {
+            backend_processing_time_sketch: if config.backend_processing_time_percentiles {
+                Some(QuantileSketch::default())
+            } else {
+                None
+            },
+            distinct_clients: if config.distinct_clients {
+                Some(HyperLogLog::default())
+            } else {
+                None
+            },
+            ..AggregateMetrics::default()
+        }
+    }
+
+    fn add(&mut self, record: &LogRecord) {
+        self.requests += 1;
+        self.sent_bytes += record.sent_bytes;
+        self.received_bytes += record.received_bytes;
+        if record.backend_processing_time >= 0.0 {
+            self.backend_processing_time += f64::from(record.backend_processing_time);
+            if let Some(ref mut sketch) = self.backend_processing_time_sketch {
+                sketch.add(f64::from(record.backend_processing_time));
+            }
+        }
+        if let Some(ref mut distinct_clients) = self.distinct_clients {
+            distinct_clients.insert(&record.client_address);
+        }
+    }
+
+    pub fn merge(&mut self, other: &AggregateMetrics) {
+        self.requests += other.requests;
+        self.sent_bytes += other.sent_bytes;
+        self.received_bytes += other.received_bytes;
+        self.backend_processing_time += other.backend_processing_time;
+        if let Some(ref other_sketch) = other.backend_processing_time_sketch {
+            self.backend_processing_time_sketch
+                .get_or_insert_with(QuantileSketch::default)
+                .merge(other_sketch);
+        }
+        if let Some(ref other_distinct_clients) = other.distinct_clients {
+            self.distinct_clients
+                .get_or_insert_with(HyperLogLog::default)
+                .merge(other_distinct_clients);
+        }
+    }
+}
+
+/// The fields records can be grouped by.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Dimension {
+    System,
+    Time,
+    Client,
+    Backend,
+    /// The numeric path segment of the request, such as the camera id in `/404299/full`.
+    Resource,
+    /// The path segment following the resource id, such as `full` or `half`.
+    Rendition,
+}
+
+pub const DIMENSION_NAMES: [&str; 6] =
+    ["system", "time", "client", "backend", "resource", "rendition"];
+pub const DEFAULT_GROUP_BY: [Dimension; 3] =
+    [Dimension::System, Dimension::Time, Dimension::Client];
+
+impl Dimension {
+    fn value(&self,
+             config: &AggregationConfig,
+             record: &LogRecord,
+             system_name: &str)
+             -> DimensionValue {
+        match *self {
+            Dimension::System => DimensionValue::System(system_name.to_owned()),
+            Dimension::Time => {
+                let timestamp = record.timestamp.with_timezone(&config.time_zone);
+                DimensionValue::Time(config.time_bucket.truncate(&timestamp), config.time_bucket)
+            }
+            Dimension::Client => DimensionValue::Client(record.client_address),
+            Dimension::Backend => DimensionValue::Backend(record.backend_address),
+            Dimension::Resource => {
+                let (resource_id, _) = parse_resource(record.request_url);
+                DimensionValue::Resource(resource_id.map(str::to_owned))
+            }
+            Dimension::Rendition => {
+                let (_, rendition) = parse_resource(record.request_url);
+                DimensionValue::Rendition(rendition.map(str::to_owned))
+            }
+        }
+    }
+}
+
+// The resource id is the first path segment made up of digits and the rendition is the segment
+// after it.
+fn parse_resource(request_url: &str) -> (Option<&str>, Option<&str>) {
+    let mut segments = system_names::request_path(request_url)
+        .split('/')
+        .skip_while(|segment| {
+            segment.is_empty() || !segment.bytes().all(|byte| byte.is_ascii_digit())
+        });
+    let resource_id = segments.next();
+    let rendition = resource_id.and_then(|_| segments.next()).filter(|segment| !segment.is_empty());
+    (resource_id, rendition)
+}
+
+impl Display for Dimension {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Dimension::System => write!(f, "system"),
+            Dimension::Time => write!(f, "time"),
+            Dimension::Client => write!(f, "client"),
+            Dimension::Backend => write!(f, "backend"),
+            Dimension::Resource => write!(f, "resource"),
+            Dimension::Rendition => write!(f, "rendition"),
+        }
+    }
+}
+
+impl FromStr for Dimension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dimension, String> {
+        match s {
+            "system" => Ok(Dimension::System),
+            "time" => Ok(Dimension::Time),
+            "client" => Ok(Dimension::Client),
+            "backend" => Ok(Dimension::Backend),
+            "resource" => Ok(Dimension::Resource),
+            "rendition" => Ok(Dimension::Rendition),
+            _ => Err(format!("{} is not one of the dimensions {:?}.", s, DIMENSION_NAMES)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DimensionValue {
+    System(String),
+    /// The start of the time bucket a record falls into, in the configured time zone.
+    Time(DateTime<Tz>, TimeBucket),
+    Client(IpAddr),
+    Backend(Option<SocketAddr>),
+    Resource(Option<String>),
+    Rendition(Option<String>),
+}
+
+impl Display for DimensionValue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            DimensionValue::System(ref system_name) => write!(f, "{}", system_name),
+            DimensionValue::Time(ref start, _) if start.timezone() != Tz::UTC => {
+                write!(f, "{}", start.format("%Y-%m-%dT%H:%M:%S%:z"))
+            }
+            DimensionValue::Time(ref start, ref time_bucket) => {
+                write!(f, "{}", start.format(time_bucket.timestamp_format()))
+            }
+            DimensionValue::Client(ref client_address) => write!(f, "{}", client_address),
+            DimensionValue::Backend(Some(ref backend_address)) => {
+                write!(f, "{}", backend_address)
+            }
+            DimensionValue::Resource(Some(ref resource_id)) => write!(f, "{}", resource_id),
+            DimensionValue::Rendition(Some(ref rendition)) => write!(f, "{}", rendition),
+            DimensionValue::Backend(None) |
+            DimensionValue::Resource(None) |
+            DimensionValue::Rendition(None) => write!(f, "-"),
+        }
+    }
+}
+
+/// Settings that control how the FileAggregators turn records into aggregates.
+#[derive(Clone, Debug)]
+pub struct AggregationConfig {
+    /// The format of every file. When it is `None` the format is detected for each file.
+    pub log_format: Option<LogFormat>,
+    pub group_by: Vec<Dimension>,
+    pub time_bucket: TimeBucket,
+    /// The zone the time buckets start and end in.
+    pub time_zone: Tz,
+    /// Keep a quantile sketch of the backend processing times of each aggregate.
+    pub backend_processing_time_percentiles: bool,
+    /// Estimate the number of distinct clients of each aggregate.
+    pub distinct_clients: bool,
+    /// The filters a record has to pass to be counted.
+    pub record_filters: Vec<RecordFilter>,
+    /// When it is set, requests carrying a registered token are billed to the token's account
+    /// whatever their system name.
+    pub token_registry: Option<TokenRegistry>,
+    /// The rules that find the raw system name of a request, tried in order.
+    pub system_extraction: Vec<ExtractionRule>,
+    pub system_name_normalization: SystemNameNormalization,
+    pub system_aliases: Option<SystemAliases>,
+    /// The name system names without an alias are reported under. When it is `None` they are
+    /// reported under their own name.
+    pub unmapped_system: Option<String>,
+    /// The name records without a system name are reported under.
+    pub undefined_system: String,
+}
+
+impl Default for AggregationConfig {
+    fn default() -> AggregationConfig {
+        AggregationConfig {
+            log_format: None,
+            group_by: DEFAULT_GROUP_BY.to_vec(),
+            time_bucket: TimeBucket::Day,
+            time_zone: Tz::UTC,
+            backend_processing_time_percentiles: false,
+            distinct_clients: false,
+            record_filters: Vec::new(),
+            token_registry: None,
+            system_extraction: system_names::default_extraction_rules(),
+            system_name_normalization: SystemNameNormalization::default(),
+            system_aliases: None,
+            unmapped_system: None,
+            undefined_system: UNDEFINED_SYSTEM.to_owned(),
+        }
+    }
+}
+
+impl AggregationConfig {
+    /// Returns the name a normalized system name is reported under.
+    fn account(&self, system_name: String) -> String {
+        match self.system_aliases {
+            Some(ref aliases) => {
+                match aliases.account(&system_name) {
+                    Some(account) => account.to_owned(),
+                    None => self.unmapped_system.clone().unwrap_or(system_name),
+                }
+            }
+            None => system_name,
+        }
+    }
+}
+
+/// The length of the time periods the records are counted over.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum TimeBucket {
+    Minute,
+    Hour,
+    Day,
+    /// Weeks start on Monday.
+    Week,
+    Month,
+}
+
+pub const TIME_BUCKET_NAMES: [&str; 5] = ["minute", "hour", "day", "week", "month"];
+
+impl TimeBucket {
+    /// Returns the start of the bucket the timestamp falls into. Buckets follow the wall clock
+    /// of the timestamp's time zone, so a day bucket can be 23 or 25 hours long.
+    pub fn truncate(&self, timestamp: &DateTime<Tz>) -> DateTime<Tz> {
+        let local = timestamp.naive_local();
+        let date = local.date();
+        let start_date = match *self {
+            TimeBucket::Week => date.week(Weekday::Mon).first_day(),
+            TimeBucket::Month => date.with_day(1).unwrap(),
+            _ => date,
+        };
+        let start = match *self {
+                TimeBucket::Minute => start_date.and_hms_opt(local.hour(), local.minute(), 0),
+                TimeBucket::Hour => start_date.and_hms_opt(local.hour(), 0, 0),
+                _ => start_date.and_hms_opt(0, 0, 0),
+            }
+            .unwrap();
+
+        let time_zone = timestamp.timezone();
+        match time_zone.from_local_datetime(&start) {
+            LocalResult::Single(start) => start,
+            // The clocks went back over the start. The repeated minutes and hours are buckets
+            // of their own, longer buckets start at the first occurrence.
+            LocalResult::Ambiguous(earliest, latest) => {
+                match *self {
+                    TimeBucket::Minute | TimeBucket::Hour if latest <= *timestamp => latest,
+                    _ => earliest,
+                }
+            }
+            // The clocks went forward over the start, so the bucket starts when they did.
+            LocalResult::None => {
+                let offset_before = time_zone
+                    .offset_from_utc_datetime(&(start - Duration::days(1)))
+                    .fix();
+                time_zone.from_utc_datetime(&(start - offset_before))
+            }
+        }
+    }
+
+    /// The strftime format the start of a bucket is written to the output with when the time
+    /// zone is UTC. Weeks are written as the date of their Monday.
+    pub fn timestamp_format(&self) -> &'static str {
+        match *self {
+            TimeBucket::Minute => "%Y-%m-%dT%H:%M",
+            TimeBucket::Hour => "%Y-%m-%dT%H",
+            TimeBucket::Day | TimeBucket::Week => "%Y-%m-%d",
+            TimeBucket::Month => "%Y-%m",
+        }
+    }
+}
+
+impl Display for TimeBucket {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            TimeBucket::Minute => write!(f, "minute"),
+            TimeBucket::Hour => write!(f, "hour"),
+            TimeBucket::Day => write!(f, "day"),
+            TimeBucket::Week => write!(f, "week"),
+            TimeBucket::Month => write!(f, "month"),
+        }
+    }
+}
+
+impl FromStr for TimeBucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TimeBucket, String> {
+        match s {
+            "minute" => Ok(TimeBucket::Minute),
+            "hour" => Ok(TimeBucket::Hour),
+            "day" => Ok(TimeBucket::Day),
+            "week" => Ok(TimeBucket::Week),
+            "month" => Ok(TimeBucket::Month),
+            _ => Err(format!("{} is not one of the time buckets {:?}.", s, TIME_BUCKET_NAMES)),
+        }
+    }
+}
+
+/// The load balancer log layouts counter knows how to parse.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum LogFormat {
+    ClassicELB,
+    ALB,
+}
+
+pub const LOG_FORMAT_NAMES: [&str; 2] = ["elb", "alb"];
+
+impl LogFormat {
+    const ALL: [LogFormat; 2] = [LogFormat::ClassicELB, LogFormat::ALB];
+
+    /// Picks the format that parses the most of the sample records.
+    ///
+    /// Returns `None` if none of the formats can parse a single record of the sample, which is
+    /// the case for empty files.
+    pub fn detect(sample: &[&str]) -> Option<LogFormat> {
+        LogFormat::ALL
+            .iter()
+            .map(|format| {
+                (sample.iter().filter(|record| format.parses(record)).count(), *format)
+            })
+            .filter(|&(num_parsed, _)| num_parsed > 0)
+            .max_by_key(|&(num_parsed, _)| num_parsed)
+            .map(|(_, format)| format)
+    }
+
+    fn parses(&self, record: &str) -> bool {
+        match *self {
+            LogFormat::ClassicELB => {
+                elp::parse_record(&with_ipv4_client_address(record).0).is_ok()
+            }
+            LogFormat::ALB => alb::parse_record(record).is_ok(),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            LogFormat::ClassicELB => write!(f, "elb"),
+            LogFormat::ALB => write!(f, "alb"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "elb" => Ok(LogFormat::ClassicELB),
+            "alb" => Ok(LogFormat::ALB),
+            _ => Err(format!("{} is not one of the log formats {:?}.", s, LOG_FORMAT_NAMES)),
+        }
+    }
+}
+
+pub fn try_parse_record(possible_record: &str,
+                        log_format: LogFormat,
+                        config: &AggregationConfig,
+                        dst_agg: &mut FileAggregation) {
+    match log_format {
+        LogFormat::ClassicELB => try_parse_elb_record(possible_record, config, dst_agg),
+        LogFormat::ALB => try_parse_alb_record(possible_record, config, dst_agg),
+    }
+}
+
+fn try_parse_elb_record(possible_record: &str,
+                        config: &AggregationConfig,
+                        dst_agg: &mut FileAggregation) {
+    let (ipv4_record, ipv6_client_address) = with_ipv4_client_address(possible_record);
+    match elp::parse_record(&ipv4_record) {
         Ok(elb_record) => {
-            let aer =
-                AggregateELBRecord::new(elb_record.timestamp,
-                                        *elb_record.client_address.ip(),
-                                        parse_system_name(elb_record.request_url)
-                                            .unwrap_or_else(|| "UNDEFINED_SYSTEM".to_owned()));
-            aggregate_record(aer, dst_agg);
+            let record = LogRecord {
+                timestamp: elp_timestamp(&elb_record),
+                client_address: ipv6_client_address
+                    .map(IpAddr::V6)
+                    .unwrap_or_else(|| IpAddr::V4(*elb_record.client_address.ip())),
+                backend_address: Some(SocketAddr::V4(elb_record.backend_address)),
+                status_code: elb_record.elb_status_code,
+                request_method: elb_record.request_method,
+                request_url: elb_record.request_url,
+                sent_bytes: elb_record.sent_bytes,
+                received_bytes: elb_record.received_bytes,
+                backend_processing_time: elb_record.backend_processing_time,
+            };
+            aggregate_log_record(&record, config, dst_agg);
+        }
+        Err(_) => println_stderr!("{:?}", possible_record),
+    }
+}
+
+// elp only understands IPv4 client addresses so an IPv6 client address is swapped for a
+// placeholder while the rest of the record is parsed.
+fn with_ipv4_client_address<'a>(record: &'a str) -> (Cow<'a, str>, Option<Ipv6Addr>) {
+    match find_ipv6_client_address(record) {
+        Some((start, end, client_address)) => {
+            let ipv4_record = format!("{}{}{}",
+                                      &record[..start],
+                                      IPV6_CLIENT_PLACEHOLDER,
+                                      &record[end..]);
+            (Cow::Owned(ipv4_record), Some(client_address))
+        }
+        None => (Cow::Borrowed(record), None),
+    }
+}
+
+const IPV6_CLIENT_PLACEHOLDER: &str = "0.0.0.0:0";
+const ELB_CLIENT_ADDRESS_FIELD: usize = 2;
+
+// Returns the byte range of the client address field of a Classic ELB record and the address
+// if the client is an IPv6 address.
+fn find_ipv6_client_address(record: &str) -> Option<(usize, usize, Ipv6Addr)> {
+    let mut start = 0;
+    for (field_idx, field) in record.split(' ').enumerate() {
+        if field_idx == ELB_CLIENT_ADDRESS_FIELD {
+            return match parse_socket_address(field) {
+                Some(SocketAddr::V6(client_address)) => {
+                    Some((start, start + field.len(), *client_address.ip()))
+                }
+                _ => None,
+            };
+        }
+        start += field.len() + 1;
+    }
+
+    None
+}
+
+/// Parses an `address:port` pair where an IPv6 address may or may not be enclosed in brackets.
+pub fn parse_socket_address(src_str: &str) -> Option<SocketAddr> {
+    src_str.parse::<SocketAddr>().ok().or_else(|| {
+        src_str.rfind(':').and_then(|idx| {
+            match (src_str[..idx].parse::<Ipv6Addr>(), src_str[idx + 1..].parse::<u16>()) {
+                (Ok(ip), Ok(port)) => Some(SocketAddr::new(IpAddr::V6(ip), port)),
+                _ => None,
+            }
+        })
+    })
+}
+
+/// elp is built against an older chrono, so its timestamps are carried over through their
+/// Unix time.
+fn elp_timestamp(elb_record: &elp::ELBRecord) -> DateTime<Utc> {
+    Utc.timestamp_opt(elb_record.timestamp.timestamp(),
+                      elb_record.timestamp.timestamp_subsec_nanos())
+        .unwrap()
+}
+
+fn try_parse_alb_record(possible_record: &str,
+                        config: &AggregationConfig,
+                        dst_agg: &mut FileAggregation) {
+    match alb::parse_record(possible_record) {
+        Ok(alb_record) => {
+            let record = LogRecord {
+                timestamp: alb_record.timestamp,
+                client_address: alb_record.client_address.ip(),
+                backend_address: alb_record.target_address,
+                status_code: alb_record.elb_status_code,
+                request_method: alb_record.request_method,
+                request_url: alb_record.request_url,
+                sent_bytes: alb_record.sent_bytes,
+                received_bytes: alb_record.received_bytes,
+                backend_processing_time: alb_record.target_processing_time,
+            };
+            aggregate_log_record(&record, config, dst_agg);
         }
         Err(ref errs) => println_stderr!("{:?}", errs.record),
     }
 }
 
-lazy_static! {
-    static ref SYSTEM_REGEX: Regex = Regex::new(r"(?i)system=([^&]*)").unwrap();
+const UNDEFINED_SYSTEM: &str = "UNDEFINED_SYSTEM";
+
+// The first rule that finds a system name wins.
+fn parse_system_name<'a>(src_str: &'a str, rules: &'a [ExtractionRule]) -> Option<&'a str> {
+    rules.iter().filter_map(|rule| rule.extract(src_str)).next()
+}
+
+fn aggregate_log_record(record: &LogRecord,
+                        config: &AggregationConfig,
+                        dst_agg: &mut FileAggregation) {
+    if let Some(index) = record_filters::rejecting_filter(&config.record_filters, record) {
+        if dst_agg.rejected_records.len() < config.record_filters.len() {
+            dst_agg.rejected_records.resize(config.record_filters.len(), 0);
+        }
+        dst_agg.rejected_records[index] += 1;
+        return;
+    }
+
+    let registered_account = config.token_registry
+        .as_ref()
+        .and_then(|registry| {
+            registered_account(record.request_url, registry, &mut dst_agg.unknown_tokens)
+        });
+    if let Some(account) = registered_account {
+        aggregate_record(AggregateELBRecord::new(config, record, &account),
+                         record,
+                         config,
+                         &mut dst_agg.aggregation);
+        return;
+    }
+
+    let system_name = match parse_system_name(record.request_url, &config.system_extraction) {
+        Some(raw_system_name) => {
+            // The variants are those merged by the normalization, before the aliases apply.
+            let system_name = config.system_name_normalization.normalize(raw_system_name);
+            system_names::record_variant(raw_system_name,
+                                         &system_name,
+                                         &mut dst_agg.system_name_variants);
+            config.account(system_name)
+        }
+        None => config.undefined_system.clone(),
+    };
+    aggregate_record(AggregateELBRecord::new(config, record, &system_name),
+                     record,
+                     config,
+                     &mut dst_agg.aggregation);
 }
 
-fn parse_system_name(src_str: &str) -> Option<String> {
-    SYSTEM_REGEX
-        .captures(src_str)
-        .and_then(|cap| cap.get(1).map(|sys| sys.as_str().to_string()))
+// Requests whose token isn't registered are counted by token hash and fall back to their
+// system name.
+fn registered_account(request_url: &str,
+                      registry: &TokenRegistry,
+                      unknown_tokens: &mut HashMap<String, u64>)
+                      -> Option<String> {
+    let token = system_names::query_param(request_url, &registry.param)
+        .filter(|token| !token.is_empty())?;
+    let token_hash = system_names::hash_token(token);
+    match registry.account(&token_hash) {
+        Some(account) => Some(account.to_owned()),
+        None => {
+            *unknown_tokens.entry(token_hash).or_insert(0) += 1;
+            None
+        }
+    }
 }
 
 pub fn merge_aggregates(src_aggs: &ELBRecordAggregation,
-                        dst_aggs: &mut ELBRecordAggregation)
-                        -> () {
+                        dst_aggs: &mut ELBRecordAggregation) {
     for (agg_key, agg_val) in src_aggs {
-        let total = dst_aggs.entry(agg_key.clone()).or_insert(0);
-        *total += *agg_val;
+        dst_aggs.entry(agg_key.clone()).or_default().merge(agg_val);
     }
 }
 
 fn aggregate_record(aggregate_record: AggregateELBRecord,
-                    dst_aggs: &mut ELBRecordAggregation)
-                    -> () {
-    let total = dst_aggs.entry(aggregate_record).or_insert(0);
-    *total += 1;
+                    record: &LogRecord,
+                    config: &AggregationConfig,
+                    dst_aggs: &mut ELBRecordAggregation) {
+    dst_aggs
+        .entry(aggregate_record)
+        .or_insert_with(|| AggregateMetrics::new(config))
+        .add(record);
 }
 
 #[cfg(test)]
@@ -68,44 +654,473 @@ mod handle_parsing_result_tests {
 
     extern crate elp;
 
-    use std::collections::HashMap;
+    use FileAggregation;
+    use std::net::IpAddr;
+    use std::path::Path;
+    use record_filters::RecordFilter;
+    use system_names::{hash_token, SystemAliases, SystemNameNormalization, TokenRegistry};
+    use test_common;
+    use super::{Dimension, DimensionValue, TimeBucket};
 
-    const GOOD_RECORD0: &'static str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+    const GOOD_RECORD0: &str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
                     172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
                     \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\"";
-    const GOOD_RECORD1: &'static str = "2016-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+    const GOOD_RECORD1: &str = "2016-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
                     172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
                     \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\"";
     #[test]
     fn handle_parsing_result_should_not_alter_the_dst_agg_when_passed_bad_records() {
-        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig::default();
         let bad_record = "";
 
-        super::try_parse_record(GOOD_RECORD0, &mut dst_agg);
-        super::try_parse_record(bad_record, &mut dst_agg);
+        super::try_parse_record(GOOD_RECORD0, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        super::try_parse_record(bad_record, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+
+        assert_eq!(dst_agg.aggregation.len(), 1)
+    }
+
+    const GOOD_ALB_RECORD: &str = "http 2015-08-15T23:43:05.302180Z app/alb-name/50dc6c495c0c9188 \
+                    172.16.1.6:54814 172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\" \
+                    \"-\" - - arn:aws:elasticloadbalancing:us-east-1:123456789012:targetgroup/tg/1 \
+                    \"Root=1-58337281-1d84f3d73c47ec4e58577259\"";
+
+    #[test]
+    fn handle_parsing_result_should_produce_the_same_key_for_classic_and_alb_records() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig::default();
+
+        super::try_parse_record(GOOD_RECORD0, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        super::try_parse_record(GOOD_ALB_RECORD, super::LogFormat::ALB, &config, &mut dst_agg);
+
+        assert_eq!(dst_agg.aggregation.len(), 1);
+        assert_eq!(dst_agg.aggregation.values().next().unwrap().requests, 2)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_accept_ipv6_client_addresses() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig::default();
+        let ipv6_record = GOOD_RECORD0.replace("172.16.1.6:54814", "[2001:db8::ff00:42:8329]:54814");
+        let ipv6_alb_record = GOOD_ALB_RECORD.replace("172.16.1.6:54814",
+                                                      "2001:db8::ff00:42:8329:54814");
+
+        super::try_parse_record(&ipv6_record, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        super::try_parse_record(&ipv6_alb_record, super::LogFormat::ALB, &config, &mut dst_agg);
+
+        assert_eq!(dst_agg.aggregation.len(), 1);
+        let (aggregate, total) = dst_agg.aggregation.iter().next().unwrap();
+        assert_eq!(aggregate.dimensions[2],
+                   DimensionValue::Client("2001:db8::ff00:42:8329".parse::<IpAddr>().unwrap()));
+        assert_eq!(total.requests, 2)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_key_the_aggregates_by_the_group_by_dimensions() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig {
+            group_by: vec![Dimension::Backend, Dimension::System],
+            ..super::AggregationConfig::default()
+        };
+
+        super::try_parse_record(GOOD_RECORD0, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        super::try_parse_record(GOOD_RECORD1, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+
+        assert_eq!(dst_agg.aggregation.len(), 1);
+        let (aggregate, total) = dst_agg.aggregation.iter().next().unwrap();
+        assert_eq!(aggregate.dimensions,
+                   vec![DimensionValue::Backend(Some("172.16.1.5:9000".parse().unwrap())),
+                        DimensionValue::System("UNDEFINED_SYSTEM".to_owned())]);
+        assert_eq!(total.requests, 2)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_report_system_names_under_their_aliases() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig {
+            group_by: vec![Dimension::System],
+            system_aliases: Some(test_aliases()),
+            ..super::AggregationConfig::default()
+        };
+        let wpix_record = GOOD_RECORD0.replace("param0=p0", "system=WPIX%202");
+        let wpix_record_variant = GOOD_RECORD0.replace("param0=p0", "system=wpix+2");
+
+        super::try_parse_record(&wpix_record, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        super::try_parse_record(&wpix_record_variant,
+                                super::LogFormat::ClassicELB,
+                                &config,
+                                &mut dst_agg);
+
+        assert_eq!(dst_agg.aggregation.len(), 1);
+        let (aggregate, total) = dst_agg.aggregation.iter().next().unwrap();
+        assert_eq!(aggregate.dimensions, vec![DimensionValue::System("WPIX".to_owned())]);
+        assert_eq!(total.requests, 2);
+        assert_eq!(dst_agg.system_name_variants["wpix 2"].len(), 2)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_use_the_first_extraction_rule_that_finds_a_system_name() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig {
+            group_by: vec![Dimension::System],
+            system_extraction: vec!["param:account".parse().unwrap(),
+                                    "path:1".parse().unwrap()],
+            ..super::AggregationConfig::default()
+        };
+        let account_record = GOOD_RECORD0.replace("param0=p0", "account=WPIX");
+
+        super::try_parse_record(&account_record,
+                                super::LogFormat::ClassicELB,
+                                &config,
+                                &mut dst_agg);
+        super::try_parse_record(GOOD_RECORD0, super::LogFormat::ClassicELB, &config, &mut dst_agg);
 
-        assert_eq!(dst_agg.len(), 1)
+        let mut system_names = dst_agg.aggregation
+            .keys()
+            .map(|aggregate| aggregate.dimensions[0].to_string())
+            .collect::<Vec<_>>();
+        system_names.sort();
+        assert_eq!(system_names, vec!["path1", "wpix"])
+    }
+
+    #[test]
+    fn handle_parsing_result_should_report_unmapped_and_undefined_systems_as_configured() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig {
+            group_by: vec![Dimension::System],
+            system_aliases: Some(test_aliases()),
+            unmapped_system: Some("UNMAPPED".to_owned()),
+            undefined_system: "NO_SYSTEM".to_owned(),
+            ..super::AggregationConfig::default()
+        };
+        let unmapped_record = GOOD_RECORD0.replace("param0=p0", "system=babao");
+        let other_unmapped_record = GOOD_RECORD0.replace("param0=p0", "system=kcscout2");
+
+        for record in &[&unmapped_record, &other_unmapped_record] {
+            super::try_parse_record(record, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        }
+        super::try_parse_record(GOOD_RECORD0, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+
+        let mut system_names = dst_agg.aggregation
+            .keys()
+            .map(|aggregate| aggregate.dimensions[0].to_string())
+            .collect::<Vec<_>>();
+        system_names.sort();
+        assert_eq!(system_names, vec!["NO_SYSTEM", "UNMAPPED"]);
+        assert!(!dst_agg.system_name_variants.contains_key("UNMAPPED"))
+    }
+
+    #[test]
+    fn handle_parsing_result_should_count_the_records_each_filter_rejected() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig {
+            record_filters: vec![RecordFilter::Status(vec!["2xx".parse().unwrap()]),
+                                 RecordFilter::Method(vec!["GET".to_owned()])],
+            ..super::AggregationConfig::default()
+        };
+        let head_record = GOOD_RECORD0.replace("\"GET ", "\"HEAD ");
+        let error_record = GOOD_RECORD0.replace(" 200 200 ", " 503 503 ");
+
+        for record in &[GOOD_RECORD0, &head_record, &error_record, &error_record] {
+            super::try_parse_record(record, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        }
+
+        assert_eq!(dst_agg.aggregation.values().map(|total| total.requests).sum::<i64>(), 1);
+        assert_eq!(dst_agg.rejected_records, vec![2, 1]);
+    }
+
+    #[test]
+    fn handle_parsing_result_should_group_by_the_resource_and_rendition_of_the_request() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig {
+            group_by: vec![Dimension::Resource, Dimension::Rendition],
+            ..super::AggregationConfig::default()
+        };
+        let camera_record = GOOD_RECORD0.replace("/path0/path1", "/404299/full");
+
+        super::try_parse_record(&camera_record,
+                                super::LogFormat::ClassicELB,
+                                &config,
+                                &mut dst_agg);
+        super::try_parse_record(GOOD_RECORD0, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+
+        let mut resources = dst_agg.aggregation
+            .keys()
+            .map(|aggregate| aggregate.dimensions.iter().map(|d| d.to_string()).collect())
+            .collect::<Vec<Vec<String>>>();
+        resources.sort();
+        assert_eq!(resources, vec![vec!["-", "-"], vec!["404299", "full"]])
+    }
+
+    #[test]
+    fn handle_parsing_result_should_attribute_registered_tokens_and_count_unknown_ones() {
+        let mut dst_agg = FileAggregation::default();
+        let registry_path = Path::new(test_common::TEST_TOKEN_REGISTRY_FILE);
+        let config = super::AggregationConfig {
+            group_by: vec![Dimension::System],
+            token_registry: Some(TokenRegistry::from_file(registry_path, "pubtoken").unwrap()),
+            ..super::AggregationConfig::default()
+        };
+        let registered_record = GOOD_RECORD0.replace("param0=p0", "pubtoken=abc123&system=kcscout");
+        let unknown_record = GOOD_RECORD0.replace("param0=p0", "pubtoken=leaked&system=kcscout");
+
+        for record in &[&registered_record, &unknown_record, &unknown_record] {
+            super::try_parse_record(record, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        }
+
+        let mut system_names = dst_agg.aggregation
+            .keys()
+            .map(|aggregate| aggregate.dimensions[0].to_string())
+            .collect::<Vec<_>>();
+        system_names.sort();
+        assert_eq!(system_names, vec!["WPIX", "kcscout"]);
+        assert_eq!(dst_agg.unknown_tokens.get(&hash_token("leaked")), Some(&2));
+        assert_eq!(dst_agg.unknown_tokens.len(), 1)
+    }
+
+    fn test_aliases() -> SystemAliases {
+        SystemAliases::from_file(Path::new(test_common::TEST_SYSTEM_ALIASES_FILE),
+                                 &SystemNameNormalization::default())
+            .unwrap()
     }
 
     #[test]
     fn handle_parsing_result_should_update_the_dst_agg_when_passed_good_records() {
-        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig::default();
+
+        super::try_parse_record(GOOD_RECORD0, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        super::try_parse_record(GOOD_RECORD1, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+
+        assert_eq!(dst_agg.aggregation.len(), 2)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_bucket_the_records_by_the_configured_time_bucket() {
+        let mut dst_agg = FileAggregation::default();
+        let config = super::AggregationConfig {
+            group_by: vec![Dimension::Time],
+            time_bucket: TimeBucket::Hour,
+            ..super::AggregationConfig::default()
+        };
+        let later_record = GOOD_RECORD0.replace("23:43:05", "23:59:59");
+
+        super::try_parse_record(GOOD_RECORD0, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+        super::try_parse_record(&later_record, super::LogFormat::ClassicELB, &config, &mut dst_agg);
+
+        assert_eq!(dst_agg.aggregation.len(), 1);
+        let (aggregate, total) = dst_agg.aggregation.iter().next().unwrap();
+        assert_eq!(aggregate.dimensions[0].to_string(), "2015-08-15T23");
+        assert_eq!(total.requests, 2)
+    }
+}
+
+#[cfg(test)]
+mod time_bucket_tests {
+
+    use chrono::{DateTime, Utc};
+    use chrono_tz::Tz;
+    use super::{DimensionValue, TimeBucket};
+
+    fn bucket_start(time_bucket: TimeBucket, timestamp: &str) -> String {
+        bucket_start_in(time_bucket, Tz::UTC, timestamp)
+    }
+
+    fn bucket_start_in(time_bucket: TimeBucket, time_zone: Tz, timestamp: &str) -> String {
+        let timestamp = timestamp.parse::<DateTime<Utc>>().unwrap().with_timezone(&time_zone);
+        DimensionValue::Time(time_bucket.truncate(&timestamp), time_bucket).to_string()
+    }
+
+    #[test]
+    fn truncate_should_return_the_start_of_the_bucket_the_timestamp_falls_into() {
+        let timestamp = "2015-08-15T23:43:05.302180Z";
+
+        assert_eq!(bucket_start(TimeBucket::Minute, timestamp), "2015-08-15T23:43");
+        assert_eq!(bucket_start(TimeBucket::Hour, timestamp), "2015-08-15T23");
+        assert_eq!(bucket_start(TimeBucket::Day, timestamp), "2015-08-15");
+        assert_eq!(bucket_start(TimeBucket::Week, timestamp), "2015-08-10");
+        assert_eq!(bucket_start(TimeBucket::Month, timestamp), "2015-08");
+    }
+
+    #[test]
+    fn truncate_should_start_weeks_on_monday_across_month_boundaries() {
+        assert_eq!(bucket_start(TimeBucket::Week, "2015-09-02T01:00:00Z"), "2015-08-31");
+        assert_eq!(bucket_start(TimeBucket::Week, "2015-08-31T00:00:00Z"), "2015-08-31");
+    }
+
+    #[test]
+    fn truncate_should_start_the_buckets_on_the_wall_clock_of_the_time_zone() {
+        let eastern = Tz::America__New_York;
+
+        assert_eq!(bucket_start_in(TimeBucket::Day, eastern, "2015-08-16T03:43:05Z"),
+                   "2015-08-15T00:00:00-04:00");
+        assert_eq!(bucket_start_in(TimeBucket::Day, eastern, "2015-12-16T03:43:05Z"),
+                   "2015-12-15T00:00:00-05:00");
+        assert_eq!(bucket_start_in(TimeBucket::Month, eastern, "2015-09-01T03:43:05Z"),
+                   "2015-08-01T00:00:00-04:00");
+    }
+
+    #[test]
+    fn truncate_should_keep_the_repeated_hour_apart_when_the_clocks_go_back() {
+        let eastern = Tz::America__New_York;
+
+        assert_eq!(bucket_start_in(TimeBucket::Hour, eastern, "2015-11-01T05:30:00Z"),
+                   "2015-11-01T01:00:00-04:00");
+        assert_eq!(bucket_start_in(TimeBucket::Hour, eastern, "2015-11-01T06:30:00Z"),
+                   "2015-11-01T01:00:00-05:00");
+        assert_eq!(bucket_start_in(TimeBucket::Day, eastern, "2015-11-01T06:30:00Z"),
+                   "2015-11-01T00:00:00-04:00");
+    }
+
+    #[test]
+    fn truncate_should_start_a_bucket_when_the_clocks_skip_over_its_start() {
+        // Sao Paulo skipped from midnight to 01:00 when daylight saving time started.
+        let sao_paulo = Tz::America__Sao_Paulo;
+
+        assert_eq!(bucket_start_in(TimeBucket::Day, sao_paulo, "2015-10-18T12:00:00Z"),
+                   "2015-10-18T01:00:00-02:00");
+    }
+
+    #[test]
+    fn time_buckets_should_round_trip_through_their_names() {
+        for name in super::TIME_BUCKET_NAMES.iter() {
+            assert_eq!(name.parse::<TimeBucket>().unwrap().to_string(), *name)
+        }
+        assert!("fortnight".parse::<TimeBucket>().is_err())
+    }
+}
+
+#[cfg(test)]
+mod parse_socket_address_tests {
+
+    use std::net::SocketAddr;
+
+    #[test]
+    fn parse_socket_address_should_parse_ipv4_addresses() {
+        assert_eq!(super::parse_socket_address("172.16.1.6:54814"),
+                   Some("172.16.1.6:54814".parse::<SocketAddr>().unwrap()))
+    }
+
+    #[test]
+    fn parse_socket_address_should_parse_ipv6_addresses_with_and_without_brackets() {
+        let expected = Some("[2001:db8::1]:443".parse::<SocketAddr>().unwrap());
+
+        assert_eq!(super::parse_socket_address("[2001:db8::1]:443"), expected);
+        assert_eq!(super::parse_socket_address("2001:db8::1:443"), expected)
+    }
+
+    #[test]
+    fn parse_socket_address_should_return_none_for_bad_addresses() {
+        assert_eq!(super::parse_socket_address("-"), None);
+        assert_eq!(super::parse_socket_address("172.16.1.6"), None);
+        assert_eq!(super::parse_socket_address("2001:db8::1:port"), None)
+    }
+}
 
-        super::try_parse_record(GOOD_RECORD0, &mut dst_agg);
-        super::try_parse_record(GOOD_RECORD1, &mut dst_agg);
+#[cfg(test)]
+mod log_format_tests {
+
+    use super::LogFormat;
+
+    const ELB_RECORD: &str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\"";
+    const ALB_RECORD: &str = "h2 2015-08-15T23:43:05.302180Z app/alb-name/50dc6c495c0c9188 \
+                    172.16.1.6:54814 - -1 -1 -1 460 - 0 0 \
+                    \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/2.0\" \
+                    \"-\" - - - \"Root=1-58337281-1d84f3d73c47ec4e58577259\"";
+
+    #[test]
+    fn detect_should_return_classic_elb_for_classic_elb_records() {
+        assert_eq!(LogFormat::detect(&[ELB_RECORD, ELB_RECORD]),
+                   Some(LogFormat::ClassicELB))
+    }
+
+    #[test]
+    fn detect_should_return_classic_elb_for_classic_elb_records_with_ipv6_clients() {
+        let ipv6_record = ELB_RECORD.replace("172.16.1.6:54814", "[2001:db8::1]:54814");
+
+        assert_eq!(LogFormat::detect(&[&ipv6_record]), Some(LogFormat::ClassicELB))
+    }
+
+    #[test]
+    fn detect_should_return_alb_for_alb_records() {
+        assert_eq!(LogFormat::detect(&[ALB_RECORD]), Some(LogFormat::ALB))
+    }
+
+    #[test]
+    fn detect_should_return_the_format_that_parses_the_most_records() {
+        assert_eq!(LogFormat::detect(&[ELB_RECORD, ALB_RECORD, ALB_RECORD, "garbage"]),
+                   Some(LogFormat::ALB))
+    }
+
+    #[test]
+    fn detect_should_return_none_when_no_records_can_be_parsed() {
+        assert_eq!(LogFormat::detect(&["garbage"]), None);
+        assert_eq!(LogFormat::detect(&[]), None)
+    }
+
+    #[test]
+    fn log_formats_should_round_trip_through_their_names() {
+        for name in super::LOG_FORMAT_NAMES.iter() {
+            assert_eq!(name.parse::<LogFormat>().unwrap().to_string(), *name)
+        }
+        assert!("nginx".parse::<LogFormat>().is_err())
+    }
+}
+
+#[cfg(test)]
+mod dimension_tests {
+
+    use chrono::TimeZone;
+    use chrono_tz::Tz;
+    use super::{Dimension, DimensionValue, TimeBucket};
+
+    #[test]
+    fn dimensions_should_round_trip_through_their_names() {
+        for name in super::DIMENSION_NAMES.iter() {
+            assert_eq!(name.parse::<Dimension>().unwrap().to_string(), *name)
+        }
+        assert!("camera".parse::<Dimension>().is_err())
+    }
+
+    #[test]
+    fn dimension_values_should_display_the_way_they_are_written_to_the_output() {
+        let day = Tz::UTC.with_ymd_and_hms(2015, 8, 15, 0, 0, 0).unwrap();
 
-        assert_eq!(dst_agg.len(), 2)
+        assert_eq!(DimensionValue::Time(day, TimeBucket::Day).to_string(), "2015-08-15");
+        assert_eq!(DimensionValue::Client("172.16.1.6".parse().unwrap()).to_string(),
+                   "172.16.1.6");
+        assert_eq!(DimensionValue::Backend(None).to_string(), "-");
+        assert_eq!(DimensionValue::Resource(Some("404299".to_owned())).to_string(), "404299");
+        assert_eq!(DimensionValue::Rendition(None).to_string(), "-");
+    }
+
+    #[test]
+    fn parse_resource_should_return_the_numeric_segment_and_the_segment_after_it() {
+        assert_eq!(super::parse_resource("http://ie.trafficland.com:80/404299/full?system=wpix"),
+                   (Some("404299"), Some("full")));
+        assert_eq!(super::parse_resource("http://ie.trafficland.com:80/v1/404299/half"),
+                   (Some("404299"), Some("half")));
+        assert_eq!(super::parse_resource("http://ie.trafficland.com:80/404299/"),
+                   (Some("404299"), None));
+        assert_eq!(super::parse_resource("http://ie.trafficland.com:80/path0/path1?param0=p0"),
+                   (None, None));
     }
 }
 
 #[cfg(test)]
 mod parse_system_name_tests {
 
+    use system_names::default_extraction_rules;
+
     #[test]
     fn parse_system_name_regex_returns_a_none_when_the_system_name_is_not_present() {
         let test_uri = "http://ie.trafficland.com:80/5435/full";
+        let rules = default_extraction_rules();
 
-        let maybe_system_name = super::parse_system_name(&test_uri);
+        let maybe_system_name = super::parse_system_name(test_uri, &rules);
 
         assert!(maybe_system_name.is_none())
     }
@@ -116,10 +1131,11 @@ mod parse_system_name_tests {
         let test_uri = format!("http://ie.trafficland.com:80/5435/full?system={}&pubtoken=alkdjf&\
             refreshRate=2000&rand=1480959017673",
                                system_name);
+        let rules = default_extraction_rules();
 
-        let maybe_system_name = super::parse_system_name(&test_uri);
+        let maybe_system_name = super::parse_system_name(&test_uri, &rules);
 
-        assert_eq!(maybe_system_name, Some(system_name))
+        assert_eq!(maybe_system_name, Some(system_name.as_str()))
     }
 }
 
@@ -127,8 +1143,11 @@ mod parse_system_name_tests {
 mod merge_aggregates_tests {
     extern crate rand;
 
-    use chrono::{DateTime, UTC};
-    use std::net::SocketAddrV4;
+    use std::net::IpAddr;
+    use chrono::TimeZone;
+    use chrono_tz::Tz;
+    use super::{DimensionValue, TimeBucket};
+    use hyperloglog::HyperLogLog;
     use self::rand::distributions::{IndependentSample, Range};
     use std::collections::HashMap;
 
@@ -155,10 +1174,39 @@ mod merge_aggregates_tests {
         assert_eq!(dst_agg.len(), src_agg.len());
         for idx in 0..num_test_records {
             let key = test_keys.get(idx).unwrap();
-            let dst_rec_val = *dst_agg.get(key).unwrap();
-            let src_rec_val = *src_agg.get(key).unwrap();
-            assert_eq!(dst_rec_val, src_rec_val * 2);
+            let dst_rec_val = dst_agg.get(key).unwrap();
+            let src_rec_val = src_agg.get(key).unwrap();
+            assert_eq!(dst_rec_val.requests, src_rec_val.requests * 2);
+            assert_eq!(dst_rec_val.sent_bytes, src_rec_val.sent_bytes * 2);
+            assert_eq!(dst_rec_val.received_bytes, src_rec_val.received_bytes * 2);
+            assert_eq!(dst_rec_val.backend_processing_time,
+                       src_rec_val.backend_processing_time * 2.0);
+        }
+    }
+
+    #[test]
+    fn merge_aggregates_counts_a_client_seen_by_both_aggs_once() {
+        let key = super::AggregateELBRecord {
+            dimensions: vec![DimensionValue::System("sys1".to_owned())],
+        };
+        let mut src_agg: super::ELBRecordAggregation = HashMap::new();
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        for (agg, clients) in [(&mut src_agg, ["172.16.1.6", "172.16.1.7"]),
+                               (&mut dst_agg, ["172.16.1.7", "172.16.1.8"])] {
+            let mut distinct_clients = HyperLogLog::default();
+            for client in &clients {
+                distinct_clients.insert(&client.parse::<IpAddr>().unwrap());
+            }
+            let metrics = super::AggregateMetrics {
+                distinct_clients: Some(distinct_clients),
+                ..super::AggregateMetrics::default()
+            };
+            agg.insert(key.clone(), metrics);
         }
+
+        super::merge_aggregates(&src_agg, &mut dst_agg);
+
+        assert_eq!(dst_agg[&key].distinct_clients.as_ref().unwrap().estimate(), 3)
     }
 
     #[test]
@@ -174,20 +1222,27 @@ mod merge_aggregates_tests {
     }
 
     fn generate_test_agg(num_records: usize) -> super::ELBRecordAggregation {
-        let mut agg = HashMap::new();
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
         for _ in 0..num_records {
             let mut thread_range = rand::thread_rng();
             let sys_id_range = Range::new(0, 7);
             let sys_id = sys_id_range.ind_sample(&mut thread_range);
             let record = super::AggregateELBRecord {
-                day: "2015-08-15T23:43:05.302180Z"
-                    .parse::<DateTime<UTC>>()
-                    .unwrap()
-                    .date(),
-                client_address: *"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip(),
-                system_name: format!("sys{}", sys_id),
+                dimensions: vec![DimensionValue::System(format!("sys{}", sys_id)),
+                                 DimensionValue::Time(Tz::UTC.with_ymd_and_hms(2015, 8, 15, 0, 0, 0)
+                                                          .unwrap(),
+                                                      TimeBucket::Day),
+                                 DimensionValue::Client("172.16.1.6".parse::<IpAddr>().unwrap())],
             };
-            super::aggregate_record(record, &mut agg);
+            let metrics = super::AggregateMetrics {
+                requests: 1,
+                sent_bytes: 7582,
+                received_bytes: 10,
+                backend_processing_time: 0.5,
+                backend_processing_time_sketch: None,
+                distinct_clients: None,
+            };
+            agg.entry(record).or_default().merge(&metrics);
         }
         agg
     }
@@ -197,59 +1252,147 @@ mod merge_aggregates_tests {
 mod aggregate_record_tests {
 
     use std::collections::HashMap;
-    use chrono::{DateTime, UTC};
-    use std::net::SocketAddrV4;
+    use std::net::IpAddr;
+    use chrono::TimeZone;
+    use chrono_tz::Tz;
+    use super::{DimensionValue, TimeBucket};
 
     #[test]
     fn inserting_two_records_with_different_values_creates_two_entries_each_recorded_once() {
         let mut agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::AggregationConfig::default();
 
         let ar0 = super::AggregateELBRecord {
-            day: "2015-08-15T23:43:05.302180Z"
-                .parse::<DateTime<UTC>>()
-                .unwrap()
-                .date(),
-            client_address: *"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip(),
-            system_name: "sys1".to_owned(),
+            dimensions: vec![DimensionValue::System("sys1".to_owned()),
+                             DimensionValue::Time(Tz::UTC.with_ymd_and_hms(2015, 8, 15, 0, 0, 0)
+                                                      .unwrap(),
+                                                  TimeBucket::Day),
+                             DimensionValue::Client("172.16.1.6".parse::<IpAddr>().unwrap())],
         };
 
         let ar1 = super::AggregateELBRecord {
-            day: "2015-08-15T23:43:05.302180Z"
-                .parse::<DateTime<UTC>>()
-                .unwrap()
-                .date(),
-            client_address: *"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip(),
-            system_name: "sys2".to_owned(),
+            dimensions: vec![DimensionValue::System("sys2".to_owned()),
+                             DimensionValue::Time(Tz::UTC.with_ymd_and_hms(2015, 8, 15, 0, 0, 0)
+                                                      .unwrap(),
+                                                  TimeBucket::Day),
+                             DimensionValue::Client("172.16.1.6".parse::<IpAddr>().unwrap())],
         };
 
-        super::aggregate_record(ar0, &mut agg);
-        super::aggregate_record(ar1, &mut agg);
+        super::aggregate_record(ar0, &test_log_record(), &config, &mut agg);
+        super::aggregate_record(ar1, &test_log_record(), &config, &mut agg);
 
         assert_eq!(agg.len(), 2);
         for (_, total) in agg {
-            assert_eq!(total, 1)
+            assert_eq!(total.requests, 1)
         }
     }
 
     #[test]
     fn inserting_two_records_with_the_same_values_increases_the_total_correctly() {
         let mut agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::AggregationConfig::default();
 
         let ar0 = super::AggregateELBRecord {
-            day: "2015-08-15T23:43:05.302180Z"
-                .parse::<DateTime<UTC>>()
-                .unwrap()
-                .date(),
-            client_address: *"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip(),
-            system_name: "sys1".to_owned(),
+            dimensions: vec![DimensionValue::System("sys1".to_owned()),
+                             DimensionValue::Time(Tz::UTC.with_ymd_and_hms(2015, 8, 15, 0, 0, 0)
+                                                      .unwrap(),
+                                                  TimeBucket::Day),
+                             DimensionValue::Client("172.16.1.6".parse::<IpAddr>().unwrap())],
         };
 
         let ar1 = ar0.clone();
         let ar3 = ar0.clone();
 
-        super::aggregate_record(ar0, &mut agg);
-        super::aggregate_record(ar1, &mut agg);
+        super::aggregate_record(ar0, &test_log_record(), &config, &mut agg);
+        super::aggregate_record(ar1, &test_log_record(), &config, &mut agg);
+
+        assert_eq!(agg[&ar3].requests, 2);
+    }
+
+    #[test]
+    fn inserting_records_sums_their_bytes_and_backend_processing_times() {
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::AggregationConfig::default();
+        let key = super::AggregateELBRecord {
+            dimensions: vec![DimensionValue::System("sys1".to_owned())],
+        };
+        let unsent_record = super::LogRecord {
+            backend_address: None,
+            backend_processing_time: -1.0,
+            ..test_log_record()
+        };
+
+        super::aggregate_record(key.clone(), &test_log_record(), &config, &mut agg);
+        super::aggregate_record(key.clone(), &test_log_record(), &config, &mut agg);
+        super::aggregate_record(key.clone(), &unsent_record, &config, &mut agg);
+
+        assert_eq!(agg[&key],
+                   super::AggregateMetrics {
+                       requests: 3,
+                       sent_bytes: 3 * 7582,
+                       received_bytes: 3 * 10,
+                       backend_processing_time: 1.0,
+                       backend_processing_time_sketch: None,
+                       distinct_clients: None,
+                   });
+    }
+
+    #[test]
+    fn inserting_records_estimates_the_distinct_clients_when_enabled() {
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::AggregationConfig {
+            distinct_clients: true,
+            ..super::AggregationConfig::default()
+        };
+        let key = super::AggregateELBRecord {
+            dimensions: vec![DimensionValue::System("sys1".to_owned())],
+        };
+        let other_client_record = super::LogRecord {
+            client_address: "172.16.1.7".parse().unwrap(),
+            ..test_log_record()
+        };
+
+        super::aggregate_record(key.clone(), &test_log_record(), &config, &mut agg);
+        super::aggregate_record(key.clone(), &test_log_record(), &config, &mut agg);
+        super::aggregate_record(key.clone(), &other_client_record, &config, &mut agg);
+
+        assert_eq!(agg[&key].distinct_clients.as_ref().unwrap().estimate(), 2)
+    }
 
-        assert_eq!(agg[&ar3], 2);
+    #[test]
+    fn inserting_records_keeps_a_sketch_of_the_backend_processing_times_when_enabled() {
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::AggregationConfig {
+            backend_processing_time_percentiles: true,
+            ..super::AggregationConfig::default()
+        };
+        let key = super::AggregateELBRecord {
+            dimensions: vec![DimensionValue::System("sys1".to_owned())],
+        };
+        let unsent_record = super::LogRecord {
+            backend_processing_time: -1.0,
+            ..test_log_record()
+        };
+
+        super::aggregate_record(key.clone(), &test_log_record(), &config, &mut agg);
+        super::aggregate_record(key.clone(), &unsent_record, &config, &mut agg);
+
+        let sketch = agg[&key].backend_processing_time_sketch.as_ref().unwrap();
+        assert_eq!(sketch.count(), 1);
+        assert!((sketch.quantile(0.5).unwrap() - 0.5).abs() < 0.5 * 0.01)
+    }
+
+    fn test_log_record() -> super::LogRecord<'static> {
+        super::LogRecord {
+            timestamp: "2015-08-15T23:43:05.302180Z".parse().unwrap(),
+            client_address: "172.16.1.6".parse().unwrap(),
+            backend_address: Some("172.16.1.5:9000".parse().unwrap()),
+            status_code: 200,
+            request_method: "GET",
+            request_url: "http://some.domain.com:80/path0/path1?system=sys1",
+            sent_bytes: 7582,
+            received_bytes: 10,
+            backend_processing_time: 0.5,
+        }
     }
 }
@@ -0,0 +1,228 @@
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite;
+use rusqlite::Connection;
+use rusqlite::types::{ToSqlOutput, Value};
+use FileAggregation;
+use output;
+use output::{OutputConfig, OutputValue};
+use record_handling::AggregationConfig;
+
+/// What is recorded about a run next to its aggregates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunMetadata {
+    pub started_at: DateTime<Utc>,
+    pub log_locations: Vec<PathBuf>,
+    pub num_files: usize,
+}
+
+// Every run adds a row to runs and its aggregates to aggregates, so the history of the runs
+// accumulates in the database. The aggregates table has a column for every dimension and
+// metric, and the columns a run didn't produce are left NULL.
+const CREATE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+        started_at TEXT NOT NULL,
+        finished_at TEXT NOT NULL,
+        log_locations TEXT NOT NULL,
+        num_files INTEGER NOT NULL,
+        num_raw_records INTEGER NOT NULL,
+        group_by TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS aggregates (
+        run_id INTEGER NOT NULL REFERENCES runs (run_id),
+        system TEXT,
+        time TEXT,
+        client TEXT,
+        backend TEXT,
+        resource TEXT,
+        rendition TEXT,
+        requests INTEGER NOT NULL,
+        sent_bytes INTEGER NOT NULL,
+        received_bytes INTEGER NOT NULL,
+        backend_processing_time REAL NOT NULL,
+        backend_processing_time_p50 REAL,
+        backend_processing_time_p95 REAL,
+        backend_processing_time_p99 REAL,
+        distinct_clients INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS aggregates_run_id ON aggregates (run_id);";
+
+impl rusqlite::ToSql for OutputValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Owned(match *self {
+            OutputValue::Text(ref text) => Value::Text(text.clone()),
+            OutputValue::Integer(integer) => Value::Integer(integer),
+            OutputValue::Float(float) => Value::Real(float),
+            OutputValue::Missing => Value::Null,
+        }))
+    }
+}
+
+/// Adds the run and its aggregates to the SQLite database at `path`, creating it if it doesn't
+/// exist, in a single transaction. Returns the id of the run.
+pub fn write_sqlite(path: &Path,
+                    run: &RunMetadata,
+                    output_config: &OutputConfig,
+                    config: &AggregationConfig,
+                    final_agg: &FileAggregation)
+                    -> rusqlite::Result<i64> {
+    let mut connection = Connection::open(path)?;
+    connection.execute_batch(CREATE_TABLES)?;
+    let transaction = connection.transaction()?;
+
+    let log_locations = run.log_locations
+        .iter()
+        .map(|location| location.display().to_string())
+        .collect::<Vec<_>>();
+    let group_by = config.group_by
+        .iter()
+        .map(|dimension| dimension.to_string())
+        .collect::<Vec<_>>();
+    transaction.execute("INSERT INTO runs (started_at, finished_at, log_locations, num_files, \
+                                           num_raw_records, group_by) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        (run.started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                         Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                         log_locations.join("\n"),
+                         run.num_files as i64,
+                         final_agg.num_raw_records as i64,
+                         group_by.join(",")))?;
+    let run_id = transaction.last_insert_rowid();
+
+    // The output column names are also the names of the table's columns.
+    let column_names = output::column_names(config);
+    let placeholders = (0..column_names.len() + 1)
+        .map(|index| format!("?{}", index + 1))
+        .collect::<Vec<_>>();
+    let insert = format!("INSERT INTO aggregates (run_id, {}) VALUES ({})",
+                         column_names.join(", "),
+                         placeholders.join(", "));
+    {
+        let mut statement = transaction.prepare(&insert)?;
+        for (aggregate, total) in output::sorted_aggregates(output_config, config, final_agg) {
+            let mut values = vec![OutputValue::Integer(run_id)];
+            values.extend(output::row(config, &aggregate.dimensions, total));
+            statement.execute(rusqlite::params_from_iter(values.iter()))?;
+        }
+    }
+    transaction.commit()?;
+
+    Ok(run_id)
+}
+
+#[cfg(test)]
+mod sqlite_output_tests {
+
+    use std::path::{Path, PathBuf};
+    use chrono::{TimeZone, Utc};
+    use chrono_tz::Tz;
+    use rusqlite::Connection;
+    use output::OutputConfig;
+    use record_handling::{AggregationConfig, Dimension};
+    use test_common::{run_int_test_in_test_dir, test_aggregation};
+    use super::RunMetadata;
+
+    fn test_run() -> RunMetadata {
+        RunMetadata {
+            started_at: Utc.with_ymd_and_hms(2015, 9, 1, 2, 0, 0).unwrap(),
+            log_locations: vec![PathBuf::from("/logs/2015/08"), PathBuf::from("-")],
+            num_files: 3,
+        }
+    }
+
+    fn test_config() -> AggregationConfig {
+        AggregationConfig {
+            group_by: vec![Dimension::System, Dimension::Time, Dimension::Backend],
+            ..AggregationConfig::default()
+        }
+    }
+
+    #[test]
+    fn write_sqlite_should_record_the_run_and_its_aggregates() {
+        run_int_test_in_test_dir(|dir| {
+            let path = Path::new(dir).join("billing.db");
+
+            let run_id = super::write_sqlite(&path,
+                                             &test_run(),
+                                             &OutputConfig::default(),
+                                             &test_config(),
+                                             &test_aggregation(&Tz::UTC))
+                .unwrap();
+
+            let connection = Connection::open(&path).unwrap();
+            let run = connection.query_row("SELECT started_at, log_locations, num_files, \
+                                                   num_raw_records, group_by \
+                                            FROM runs WHERE run_id = ?1",
+                                           [run_id],
+                                           |row| {
+                    Ok((row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, String>(4)?))
+                })
+                .unwrap();
+            assert_eq!(run,
+                       ("2015-09-01T02:00:00Z".to_owned(),
+                        "/logs/2015/08\n-".to_owned(),
+                        3,
+                        10,
+                        "system,time,backend".to_owned()));
+
+            let mut statement = connection.prepare("SELECT system, backend, requests, time, \
+                                                           distinct_clients \
+                                                    FROM aggregates ORDER BY system")
+                .unwrap();
+            let aggregates = statement.query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, Option<i64>>(4)?))
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(aggregates,
+                       vec![("kcscout".to_owned(),
+                             Some("172.16.1.5:9000".to_owned()),
+                             8,
+                             Some("2015-08-15".to_owned()),
+                             None),
+                            ("wpix".to_owned(), None, 2, Some("2015-08-15".to_owned()), None)]);
+        })
+    }
+
+    #[test]
+    fn write_sqlite_should_accumulate_the_runs_in_the_database() {
+        run_int_test_in_test_dir(|dir| {
+            let path = Path::new(dir).join("billing.db");
+            let write = || {
+                super::write_sqlite(&path,
+                                    &test_run(),
+                                    &OutputConfig::default(),
+                                    &test_config(),
+                                    &test_aggregation(&Tz::UTC))
+                    .unwrap()
+            };
+
+            let first_run_id = write();
+            let second_run_id = write();
+
+            let connection = Connection::open(&path).unwrap();
+            let num_aggregates: i64 = connection.query_row("SELECT COUNT(*) FROM aggregates \
+                                                            WHERE run_id = ?1",
+                                                           [second_run_id],
+                                                           |row| row.get(0))
+                .unwrap();
+            let num_runs: i64 = connection.query_row("SELECT COUNT(*) FROM runs", [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_ne!(first_run_id, second_run_id);
+            assert_eq!(num_aggregates, 2);
+            assert_eq!(num_runs, 2)
+        })
+    }
+}
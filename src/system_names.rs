@@ -0,0 +1,700 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use csv;
+use percent_encoding::percent_decode_str;
+use regex;
+use regex::Regex;
+use rusqlite;
+use rusqlite::{Connection, OpenFlags};
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+
+/// The raw system names that were normalized into each canonical system name.
+pub type SystemNameVariants = HashMap<String, HashSet<String>>;
+
+/// The steps applied to a system name after it has been percent-decoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SystemNameNormalization {
+    pub case_fold: bool,
+    pub trim_whitespace: bool,
+    pub unicode_nfc: bool,
+}
+
+impl Default for SystemNameNormalization {
+    fn default() -> SystemNameNormalization {
+        SystemNameNormalization {
+            case_fold: true,
+            trim_whitespace: true,
+            unicode_nfc: true,
+        }
+    }
+}
+
+impl SystemNameNormalization {
+    /// Turns the steps off, leaving the names percent-decoded only.
+    pub fn none() -> SystemNameNormalization {
+        SystemNameNormalization {
+            case_fold: false,
+            trim_whitespace: false,
+            unicode_nfc: false,
+        }
+    }
+
+    pub fn enable(&mut self, step: NormalizationStep) {
+        match step {
+            NormalizationStep::CaseFold => self.case_fold = true,
+            NormalizationStep::TrimWhitespace => self.trim_whitespace = true,
+            NormalizationStep::UnicodeNFC => self.unicode_nfc = true,
+        }
+    }
+
+    /// Percent-decodes a raw system name from a query string and applies the enabled steps.
+    /// A `+` is decoded as a space, the way HTML forms encode one.
+    pub fn normalize(&self, raw_system_name: &str) -> String {
+        let plus_decoded = if raw_system_name.contains('+') {
+            Cow::Owned(raw_system_name.replace('+', " "))
+        } else {
+            Cow::Borrowed(raw_system_name)
+        };
+        let mut system_name = percent_decode_str(&plus_decoded).decode_utf8_lossy().into_owned();
+        if self.trim_whitespace {
+            system_name = system_name.trim().to_owned();
+        }
+        if self.case_fold {
+            system_name = system_name.to_lowercase();
+        }
+        if self.unicode_nfc {
+            system_name = system_name.nfc().collect();
+        }
+        system_name
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NormalizationStep {
+    CaseFold,
+    TrimWhitespace,
+    UnicodeNFC,
+}
+
+pub const NORMALIZATION_STEP_NAMES: [&str; 3] = ["case-fold", "trim", "nfc"];
+
+impl Display for NormalizationStep {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            NormalizationStep::CaseFold => write!(f, "case-fold"),
+            NormalizationStep::TrimWhitespace => write!(f, "trim"),
+            NormalizationStep::UnicodeNFC => write!(f, "nfc"),
+        }
+    }
+}
+
+impl FromStr for NormalizationStep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NormalizationStep, String> {
+        match s {
+            "case-fold" => Ok(NormalizationStep::CaseFold),
+            "trim" => Ok(NormalizationStep::TrimWhitespace),
+            "nfc" => Ok(NormalizationStep::UnicodeNFC),
+            _ => {
+                Err(format!("{} is not one of the normalization steps {:?}.",
+                            s,
+                            NORMALIZATION_STEP_NAMES))
+            }
+        }
+    }
+}
+
+/// Maps system names onto the accounts they are billed to.
+///
+/// The aliases are read from a CSV file having the header `match,pattern,account`. A row whose
+/// match is `exact` maps the system name equal to its pattern, which is normalized the same way
+/// as the names in the logs. A row whose match is `regex` maps the normalized system names its
+/// pattern matches. Exact matches win over regexes, and regexes are tried in file order.
+#[derive(Clone, Debug, Default)]
+pub struct SystemAliases {
+    exact: HashMap<String, String>,
+    patterns: Vec<(Regex, String)>,
+}
+
+#[derive(Debug)]
+pub enum AliasFileError {
+    Unreadable(csv::Error),
+    UnknownMatch { line: u64, kind: String },
+    BadPattern { line: u64, err: regex::Error },
+    MissingField { line: u64, field: &'static str },
+}
+
+impl Display for AliasFileError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            AliasFileError::Unreadable(ref err) => write!(f, "{}", err),
+            AliasFileError::UnknownMatch { line, ref kind } => {
+                write!(f, "line {}: {} is neither exact nor regex", line, kind)
+            }
+            AliasFileError::BadPattern { line, ref err } => write!(f, "line {}: {}", line, err),
+            AliasFileError::MissingField { line, field } => {
+                write!(f, "line {}: the {} is missing", line, field)
+            }
+        }
+    }
+}
+
+impl From<csv::Error> for AliasFileError {
+    fn from(err: csv::Error) -> AliasFileError {
+        AliasFileError::Unreadable(err)
+    }
+}
+
+impl SystemAliases {
+    pub fn from_file(path: &Path,
+                     normalization: &SystemNameNormalization)
+                     -> Result<SystemAliases, AliasFileError> {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path)?;
+        let mut aliases = SystemAliases::default();
+        for row in reader.records() {
+            let row = row?;
+            let line = row.position().map(|position| position.line()).unwrap_or(0);
+            let field = |index, field| {
+                match row.get(index) {
+                    Some(value) if !value.is_empty() => Ok(value),
+                    _ => Err(AliasFileError::MissingField { line, field }),
+                }
+            };
+            let (kind, pattern, account) =
+                (field(0, "match")?, field(1, "pattern")?, field(2, "account")?);
+            match kind {
+                "exact" => {
+                    aliases.exact.insert(normalization.normalize(pattern), account.to_owned());
+                }
+                "regex" => {
+                    let regex = Regex::new(pattern)
+                        .map_err(|err| AliasFileError::BadPattern { line, err })?;
+                    aliases.patterns.push((regex, account.to_owned()));
+                }
+                _ => {
+                    return Err(AliasFileError::UnknownMatch {
+                        line,
+                        kind: kind.to_owned(),
+                    })
+                }
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Returns the account a normalized system name is billed to, if it has an alias.
+    pub fn account(&self, system_name: &str) -> Option<&str> {
+        self.exact
+            .get(system_name)
+            .or_else(|| {
+                self.patterns
+                    .iter()
+                    .find(|&(regex, _)| regex.is_match(system_name))
+                    .map(|(_, account)| account)
+            })
+            .map(|account| account.as_str())
+    }
+}
+
+/// A way of finding the raw system name of a request in its URL.
+#[derive(Clone, Debug)]
+pub enum ExtractionRule {
+    /// The value of a query parameter. The parameter name is matched regardless of case.
+    QueryParam(String),
+    /// A segment of the path, counting from 0.
+    PathSegment(usize),
+    /// The `system` named capture group of a regex matched against the whole URL.
+    Pattern(Regex),
+    /// The system name a token registry maps the token in a query parameter onto.
+    TokenLookup(TokenRegistry),
+}
+
+pub const SYSTEM_CAPTURE_NAME: &str = "system";
+
+impl ExtractionRule {
+    /// Returns the raw system name the rule finds in `url`. Empty values count as not found so
+    /// that the next rule is tried.
+    pub fn extract<'a>(&'a self, url: &'a str) -> Option<&'a str> {
+        let value = match *self {
+            ExtractionRule::QueryParam(ref name) => query_param(url, name),
+            ExtractionRule::PathSegment(index) => request_path(url).split('/').nth(index + 1),
+            ExtractionRule::Pattern(ref regex) => {
+                regex.captures(url)
+                    .and_then(|captures| captures.name(SYSTEM_CAPTURE_NAME))
+                    .map(|system| system.as_str())
+            }
+            ExtractionRule::TokenLookup(ref registry) => {
+                query_param(url, &registry.param)
+                    .and_then(|token| registry.account(&hash_token(token)))
+            }
+        };
+        value.filter(|value| !value.is_empty())
+    }
+}
+
+impl FromStr for ExtractionRule {
+    type Err = String;
+
+    /// Parses `param:NAME`, `path:INDEX`, `regex:PATTERN` or `token:PARAM=FILE`, where FILE is a
+    /// token registry whose accounts are taken as system names.
+    fn from_str(s: &str) -> Result<ExtractionRule, String> {
+        let mut kind_and_arg = s.splitn(2, ':');
+        let kind = kind_and_arg.next().unwrap_or("");
+        let arg = kind_and_arg.next().unwrap_or("");
+        match kind {
+            "param" if !arg.is_empty() => Ok(ExtractionRule::QueryParam(arg.to_owned())),
+            "path" => {
+                arg.parse()
+                    .map(ExtractionRule::PathSegment)
+                    .map_err(|_| format!("{} is not a path segment index.", arg))
+            }
+            "regex" => {
+                let regex = Regex::new(arg).map_err(|err| err.to_string())?;
+                if regex.capture_names().any(|name| name == Some(SYSTEM_CAPTURE_NAME)) {
+                    Ok(ExtractionRule::Pattern(regex))
+                } else {
+                    Err(format!("{} has no capture group named {}.", arg, SYSTEM_CAPTURE_NAME))
+                }
+            }
+            "token" => {
+                let mut param_and_path = arg.splitn(2, '=');
+                match (param_and_path.next(), param_and_path.next()) {
+                    (Some(param), Some(path)) if !param.is_empty() => {
+                        TokenRegistry::from_file(Path::new(path), param)
+                            .map(ExtractionRule::TokenLookup)
+                            .map_err(|err| format!("{}: {}", path, err))
+                    }
+                    _ => Err(format!("{} is not of the form PARAM=FILE.", arg)),
+                }
+            }
+            _ => {
+                Err(format!("{} is not one of param:NAME, path:INDEX, regex:PATTERN or \
+                             token:PARAM=FILE.",
+                            s))
+            }
+        }
+    }
+}
+
+/// The rules used when none are configured, which find the `system` query parameter.
+pub fn default_extraction_rules() -> Vec<ExtractionRule> {
+    vec![ExtractionRule::QueryParam("system".to_owned())]
+}
+
+/// Returns the raw value of the first query parameter of `url` named `name`, regardless of
+/// case.
+pub fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    url.split_once('?')?
+        .1
+        .split('&')
+        .map(|param| {
+            let mut name_and_value = param.splitn(2, '=');
+            (name_and_value.next().unwrap_or(""), name_and_value.next().unwrap_or(""))
+        })
+        .find(|&(param_name, _)| param_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+/// Returns the path of `url`, which may be absolute or start with the path, without its query
+/// string.
+pub fn request_path(url: &str) -> &str {
+    let without_query = url.split('?').next().unwrap_or("");
+    match without_query.find("://") {
+        Some(scheme_end) => {
+            let authority_and_path = &without_query[scheme_end + 3..];
+            authority_and_path.find('/').map_or("", |path_start| &authority_and_path[path_start..])
+        }
+        None => without_query,
+    }
+}
+
+/// Maps the hashes of the tokens requests carry onto the accounts the requests are billed to.
+///
+/// The hash is the hex encoded SHA-256 of the token, so the registry never holds the tokens
+/// themselves. The registry is read from a SQLite database when its file has one of the
+/// `TOKEN_REGISTRY_SQLITE_EXTENSIONS`, from its `tokens` table having the columns `token_hash`
+/// and `account`, and otherwise from a CSV file having the header `token_hash,account`.
+#[derive(Clone, Debug)]
+pub struct TokenRegistry {
+    /// The query parameter that carries the token.
+    pub param: String,
+    accounts: HashMap<String, String>,
+}
+
+pub const TOKEN_REGISTRY_SQLITE_EXTENSIONS: [&str; 3] = ["db", "sqlite", "sqlite3"];
+
+#[derive(Debug)]
+pub enum TokenRegistryError {
+    UnreadableCsv(csv::Error),
+    UnreadableSqlite(rusqlite::Error),
+    /// The CSV file doesn't have the header `token_hash,account`.
+    BadHeader,
+    /// A token hash that isn't a hex encoded SHA-256, such as a token that wasn't hashed.
+    BadTokenHash(String),
+}
+
+impl Display for TokenRegistryError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            TokenRegistryError::UnreadableCsv(ref err) => write!(f, "{}", err),
+            TokenRegistryError::UnreadableSqlite(ref err) => write!(f, "{}", err),
+            TokenRegistryError::BadHeader => {
+                write!(f, "The token registry doesn't have the header token_hash,account.")
+            }
+            TokenRegistryError::BadTokenHash(ref token_hash) => {
+                write!(f, "{} is not a hex encoded SHA-256 token hash.", token_hash)
+            }
+        }
+    }
+}
+
+impl From<csv::Error> for TokenRegistryError {
+    fn from(err: csv::Error) -> TokenRegistryError {
+        TokenRegistryError::UnreadableCsv(err)
+    }
+}
+
+impl From<rusqlite::Error> for TokenRegistryError {
+    fn from(err: rusqlite::Error) -> TokenRegistryError {
+        TokenRegistryError::UnreadableSqlite(err)
+    }
+}
+
+impl TokenRegistry {
+    pub fn from_file(path: &Path, param: &str) -> Result<TokenRegistry, TokenRegistryError> {
+        let is_sqlite = path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| TOKEN_REGISTRY_SQLITE_EXTENSIONS.contains(&ext));
+        let rows = if is_sqlite {
+            read_sqlite_token_hashes(path)?
+        } else {
+            read_csv_token_hashes(path)?
+        };
+        // A table of raw tokens would load but never match, so it is refused instead.
+        if let Some((token_hash, _)) = rows.iter().find(|&(token_hash, _)| {
+            token_hash.len() != 64 || !token_hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+        }) {
+            return Err(TokenRegistryError::BadTokenHash(token_hash.clone()));
+        }
+        Ok(TokenRegistry {
+            param: param.to_owned(),
+            accounts: rows.into_iter()
+                .map(|(token_hash, account)| (token_hash.to_lowercase(), account))
+                .collect(),
+        })
+    }
+
+    pub fn account(&self, token_hash: &str) -> Option<&str> {
+        self.accounts.get(token_hash).map(|account| account.as_str())
+    }
+}
+
+fn read_csv_token_hashes(path: &Path) -> Result<Vec<(String, String)>, TokenRegistryError> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path)?;
+    if reader.headers()? != vec!["token_hash", "account"] {
+        return Err(TokenRegistryError::BadHeader);
+    }
+    let mut rows = Vec::new();
+    for row in reader.records() {
+        let row = row?;
+        if let (Some(token_hash), Some(account)) = (row.get(0), row.get(1)) {
+            rows.push((token_hash.to_owned(), account.to_owned()));
+        }
+    }
+    Ok(rows)
+}
+
+// The database is opened read only so that a mistyped path isn't created as an empty registry.
+fn read_sqlite_token_hashes(path: &Path) -> rusqlite::Result<Vec<(String, String)>> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut statement = connection.prepare("SELECT token_hash, account FROM tokens")?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Returns the hex encoded SHA-256 of a token as it appears in the query string.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Records that `raw_system_name` was normalized into `system_name`.
+pub fn record_variant(raw_system_name: &str,
+                      system_name: &str,
+                      variants: &mut SystemNameVariants) {
+    if let Some(raw_system_names) = variants.get_mut(system_name) {
+        if !raw_system_names.contains(raw_system_name) {
+            raw_system_names.insert(raw_system_name.to_owned());
+        }
+        return;
+    }
+    let mut raw_system_names = HashSet::new();
+    raw_system_names.insert(raw_system_name.to_owned());
+    variants.insert(system_name.to_owned(), raw_system_names);
+}
+
+pub fn merge_variants(src_variants: SystemNameVariants, dst_variants: &mut SystemNameVariants) {
+    for (system_name, raw_system_names) in src_variants {
+        dst_variants.entry(system_name).or_default().extend(raw_system_names);
+    }
+}
+
+#[cfg(test)]
+mod normalize_tests {
+
+    use super::SystemNameNormalization;
+
+    #[test]
+    fn normalize_should_percent_decode_the_system_name() {
+        let normalization = SystemNameNormalization::none();
+
+        assert_eq!(normalization.normalize("WPIX%202"), "WPIX 2");
+        assert_eq!(normalization.normalize("WPIX+2"), "WPIX 2");
+        assert_eq!(normalization.normalize("caf%C3%A9"), "café");
+    }
+
+    #[test]
+    fn normalize_should_merge_the_variants_of_a_name_with_the_default_steps() {
+        let normalization = SystemNameNormalization::default();
+
+        assert_eq!(normalization.normalize("WPIX%202"), "wpix 2");
+        assert_eq!(normalization.normalize("WPIX 2"), "wpix 2");
+        assert_eq!(normalization.normalize("wpix%202%20"), "wpix 2");
+    }
+
+    #[test]
+    fn normalize_should_compose_unicode_characters_when_nfc_is_enabled() {
+        let mut normalization = SystemNameNormalization::none();
+        let decomposed = "cafe%CC%81";
+
+        assert_eq!(normalization.normalize(decomposed), "cafe\u{301}");
+        normalization.unicode_nfc = true;
+        assert_eq!(normalization.normalize(decomposed), "caf\u{e9}");
+    }
+
+    #[test]
+    fn normalize_should_only_apply_the_enabled_steps() {
+        let normalization = SystemNameNormalization {
+            trim_whitespace: false,
+            ..SystemNameNormalization::default()
+        };
+
+        assert_eq!(normalization.normalize("%20WPIX"), " wpix");
+    }
+}
+
+#[cfg(test)]
+mod system_aliases_tests {
+
+    use std::path::Path;
+    use test_common;
+    use super::{AliasFileError, SystemAliases, SystemNameNormalization};
+
+    fn test_aliases() -> SystemAliases {
+        SystemAliases::from_file(Path::new(test_common::TEST_SYSTEM_ALIASES_FILE),
+                                 &SystemNameNormalization::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn account_should_map_normalized_system_names_onto_their_accounts() {
+        let aliases = test_aliases();
+
+        assert_eq!(aliases.account("wpix 2"), Some("WPIX"));
+        assert_eq!(aliases.account("kcscout"), Some("KC Scout"));
+    }
+
+    #[test]
+    fn account_should_try_the_regexes_in_file_order_after_the_exact_names() {
+        let aliases = test_aliases();
+
+        assert_eq!(aliases.account("wjla streaming"), Some("WJLA"));
+        assert_eq!(aliases.account("wjla - channel 8 streaming"), Some("WJLA"));
+        assert_eq!(aliases.account("floridadot6"), Some("Florida DOT"));
+        assert_eq!(aliases.account("nysdot"), Some("New York DOT"));
+    }
+
+    #[test]
+    fn account_should_return_none_for_names_without_an_alias() {
+        assert_eq!(test_aliases().account("babao"), None)
+    }
+
+    #[test]
+    fn from_file_should_return_an_error_for_an_unknown_match_kind() {
+        let path = Path::new(test_common::TEST_BAD_SYSTEM_ALIASES_FILE);
+
+        let result = SystemAliases::from_file(path, &SystemNameNormalization::default());
+
+        match result {
+            Err(AliasFileError::UnknownMatch { line, kind }) => {
+                assert_eq!(line, 3);
+                assert_eq!(kind, "prefix");
+            }
+            other => panic!("Expected an UnknownMatch error but got {:?}.", other),
+        }
+    }
+
+    #[test]
+    fn from_file_should_return_an_error_when_the_file_is_missing() {
+        let path = Path::new("./test_artifacts/no_such_aliases.csv");
+
+        let result = SystemAliases::from_file(path, &SystemNameNormalization::default());
+
+        assert!(result.is_err())
+    }
+}
+
+#[cfg(test)]
+mod extraction_rule_tests {
+
+    use super::ExtractionRule;
+
+    const TEST_URL: &str = "http://ie.trafficland.com:80/wpix/5435/full?System=WPIX%202&\
+                            pubtoken=abc123&refreshRate=2000";
+
+    fn extract(rule: &str, url: &str) -> Option<String> {
+        rule.parse::<ExtractionRule>().unwrap().extract(url).map(|system| system.to_owned())
+    }
+
+    #[test]
+    fn a_query_param_rule_should_extract_the_value_of_the_param_regardless_of_case() {
+        assert_eq!(extract("param:system", TEST_URL), Some("WPIX%202".to_owned()));
+        assert_eq!(extract("param:account", TEST_URL), None);
+    }
+
+    #[test]
+    fn a_path_segment_rule_should_extract_the_segment_at_the_index() {
+        assert_eq!(extract("path:0", TEST_URL), Some("wpix".to_owned()));
+        assert_eq!(extract("path:2", TEST_URL), Some("full".to_owned()));
+        assert_eq!(extract("path:3", TEST_URL), None);
+        assert_eq!(extract("path:0", "/wpix/5435/full"), Some("wpix".to_owned()));
+    }
+
+    #[test]
+    fn a_regex_rule_should_extract_the_system_capture_group() {
+        assert_eq!(extract(r"regex:refreshRate=\d+.*|/(?P<system>[a-z]+)/\d+/", TEST_URL),
+                   Some("wpix".to_owned()));
+        assert!("regex:/([a-z]+)/".parse::<ExtractionRule>().is_err());
+    }
+
+    #[test]
+    fn a_token_rule_should_extract_the_system_the_token_maps_onto() {
+        let rule = "token:pubtoken=./test_artifacts/test_token_registry.csv";
+
+        assert_eq!(extract(rule, TEST_URL), Some("WPIX".to_owned()));
+        assert_eq!(extract(rule, "http://ie.trafficland.com:80/5435/full?pubtoken=zzz"), None);
+    }
+
+    #[test]
+    fn an_empty_value_should_count_as_not_found() {
+        assert_eq!(extract("param:system", "http://ie.trafficland.com:80/full?system="), None)
+    }
+
+    #[test]
+    fn parsing_a_rule_should_fail_for_unknown_kinds_and_bad_args() {
+        assert!("header:system".parse::<ExtractionRule>().is_err());
+        assert!("path:first".parse::<ExtractionRule>().is_err());
+        assert!("param:".parse::<ExtractionRule>().is_err());
+        assert!("token:pubtoken".parse::<ExtractionRule>().is_err());
+        assert!("token:pubtoken=./test_artifacts/no_such_table.csv"
+            .parse::<ExtractionRule>()
+            .is_err());
+    }
+}
+
+#[cfg(test)]
+mod token_registry_tests {
+
+    use std::fs;
+    use std::path::Path;
+    use test_common;
+    use test_common::run_int_test_in_test_dir;
+    use super::{TokenRegistry, TokenRegistryError};
+
+    fn test_registry() -> TokenRegistry {
+        TokenRegistry::from_file(Path::new(test_common::TEST_TOKEN_REGISTRY_FILE), "pubtoken")
+            .unwrap()
+    }
+
+    #[test]
+    fn hash_token_should_return_the_hex_encoded_sha256_of_the_token() {
+        assert_eq!(super::hash_token("abc123"),
+                   "6ca13d52ca70c883e0f0bb101e425a89e8624de51db2d2392593af6a84118090")
+    }
+
+    #[test]
+    fn account_should_return_the_account_of_a_registered_token_hash_regardless_of_case() {
+        let registry = test_registry();
+
+        assert_eq!(registry.account(&super::hash_token("abc123")), Some("WPIX"));
+        assert_eq!(registry.account(&super::hash_token("def456")), Some("KC Scout"));
+        assert_eq!(registry.account(&super::hash_token("zzz")), None);
+    }
+
+    #[test]
+    fn from_file_should_read_a_sqlite_registry() {
+        let path = Path::new(test_common::TEST_SQLITE_TOKEN_REGISTRY_FILE);
+
+        let registry = TokenRegistry::from_file(path, "pubtoken").unwrap();
+
+        assert_eq!(registry.account(&super::hash_token("abc123")), Some("WPIX"));
+        assert_eq!(registry.account(&super::hash_token("def456")), Some("KC Scout"));
+        assert_eq!(registry.account(&super::hash_token("zzz")), None);
+    }
+
+    #[test]
+    fn from_file_should_return_an_error_when_the_sqlite_registry_is_missing() {
+        let path = Path::new("./test_artifacts/no_such_registry.db");
+
+        assert!(TokenRegistry::from_file(path, "pubtoken").is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn from_file_should_refuse_a_table_of_raw_tokens() {
+        run_int_test_in_test_dir(|test_dir| {
+            let raw_table = Path::new(test_dir).join("tokens.csv");
+            let unhashed_registry = Path::new(test_dir).join("registry.csv");
+            fs::write(&raw_table, "token,system\nabc123,WPIX\n").unwrap();
+            fs::write(&unhashed_registry, "token_hash,account\nabc123,WPIX\n").unwrap();
+
+            match TokenRegistry::from_file(&raw_table, "pubtoken") {
+                Err(TokenRegistryError::BadHeader) => {}
+                other => panic!("Expected a BadHeader but received {:?}.", other),
+            }
+            match TokenRegistry::from_file(&unhashed_registry, "pubtoken") {
+                Err(TokenRegistryError::BadTokenHash(token_hash)) => {
+                    assert_eq!(token_hash, "abc123")
+                }
+                other => panic!("Expected a BadTokenHash but received {:?}.", other),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod variants_tests {
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn merge_variants_should_combine_the_raw_names_of_each_system_name() {
+        let mut src_variants = HashMap::new();
+        let mut dst_variants = HashMap::new();
+        super::record_variant("WPIX%202", "wpix 2", &mut src_variants);
+        super::record_variant("WPIX 2", "wpix 2", &mut src_variants);
+        super::record_variant("WPIX 2", "wpix 2", &mut dst_variants);
+        super::record_variant("wpix%202", "wpix 2", &mut dst_variants);
+        super::record_variant("kcscout", "kcscout", &mut dst_variants);
+
+        super::merge_variants(src_variants, &mut dst_variants);
+
+        assert_eq!(dst_variants["wpix 2"].len(), 3);
+        assert_eq!(dst_variants["kcscout"].len(), 1);
+    }
+}
@@ -1,4 +1,77 @@
+extern crate names;
+
+use std::{fs, panic, sync};
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use FileAggregation;
+use record_handling::{AggregateELBRecord, AggregateMetrics, DimensionValue, TimeBucket};
+
 #[cfg(test)]
 // DO NOT MODIFY THESE PATHS. USE SYMLINKS TO REDIRECT TO SOMETHING ELSE.
-pub const TEST_LOG_FILE: &'static str = "./test_artifacts/test_elb_log_file.log";
+pub const TEST_LOG_FILE: &str = "./test_artifacts/test_elb_log_file.log";
+pub const TEST_GZIP_LOG_FILE: &str = "./test_artifacts/test_elb_log_file.log.gz";
+pub const TEST_BZIP2_LOG_FILE: &str = "./test_artifacts/test_elb_log_file.log.bz2";
+pub const TEST_ALB_LOG_FILE: &str = "./test_artifacts/test_alb_log_file.log";
 pub const TEST_LOG_FILE_AGGS: usize = 88;
+pub const TEST_SYSTEM_ALIASES_FILE: &str = "./test_artifacts/test_system_aliases.csv";
+pub const TEST_BAD_SYSTEM_ALIASES_FILE: &str = "./test_artifacts/test_bad_system_aliases.csv";
+pub const TEST_TOKEN_REGISTRY_FILE: &str = "./test_artifacts/test_token_registry.csv";
+pub const TEST_SQLITE_TOKEN_REGISTRY_FILE: &str = "./test_artifacts/test_token_registry.db";
+
+pub fn run_int_test_in_test_dir<T>(test: T)
+    where T: FnOnce(&str) + panic::UnwindSafe
+{
+    creat_test_dir();
+    let test_dir_path = create_empty_dir();
+
+    let result = panic::catch_unwind(|| test(&test_dir_path));
+
+    let _ = fs::remove_dir_all(&test_dir_path);
+
+    if let Err(err) = result {
+        panic::resume_unwind(err);
+    }
+}
+
+pub const TEST_DIR: &str = "./int_tests";
+static TEST_DIR_SYNC: sync::Once = sync::Once::new();
+fn creat_test_dir() {
+    TEST_DIR_SYNC.call_once(|| { let _ = fs::create_dir(TEST_DIR); });
+}
+
+fn create_empty_dir() -> String {
+    let path = format!("{}/{}", TEST_DIR, next_name());
+    let _ = fs::create_dir(&path);
+    path
+}
+
+pub fn next_name() -> String {
+    names::Generator::default().next().unwrap()
+}
+
+/// Aggregates grouped by system, time and backend: 2 requests to wpix, which reached no backend,
+/// and 8 to kcscout on 15 August 2015 in `time_zone`, out of 10 records.
+pub fn test_aggregation(time_zone: &Tz) -> FileAggregation {
+    let mut final_agg = FileAggregation {
+        num_raw_records: 10,
+        ..FileAggregation::default()
+    };
+    let day = time_zone.with_ymd_and_hms(2015, 8, 15, 0, 0, 0).unwrap();
+    for &(system_name, backend, requests) in &[("wpix", None, 2), ("kcscout", Some(1), 8)] {
+        let key = AggregateELBRecord {
+            dimensions: vec![DimensionValue::System(system_name.to_owned()),
+                             DimensionValue::Time(day, TimeBucket::Day),
+                             DimensionValue::Backend(backend.map(|_| {
+                                 "172.16.1.5:9000".parse().unwrap()
+                             }))],
+        };
+        let total = AggregateMetrics {
+            requests,
+            sent_bytes: 100,
+            backend_processing_time: 0.25,
+            ..AggregateMetrics::default()
+        };
+        final_agg.aggregation.insert(key, total);
+    }
+    final_agg
+}